@@ -0,0 +1,16 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+// The binary has no library to link against, so the lexer is built on its own
+#[allow(dead_code)]
+#[path = "../src/parser/lexer.rs"]
+mod lexer;
+
+fn lex_large_program(c: &mut Criterion) {
+    let source = "loop:\n  add a, 1\n  jmp loop\n".repeat(2000);
+    c.bench_function("lex 6000 lines", |b| {
+        b.iter(|| lexer::lex(black_box(&source)).unwrap())
+    });
+}
+
+criterion_group!(benches, lex_large_program);
+criterion_main!(benches);
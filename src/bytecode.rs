@@ -0,0 +1,665 @@
+use crate::parser::*;
+
+// Opcodes of the bytecode, numbered in the order they are listed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Opcode {
+    Nop,
+    Mov,
+    Fwd,
+    Rol,
+    Ror,
+    Sht,
+    Rld,
+    See,
+    Jmp,
+    Je,
+    Jg,
+    Jl,
+    Add,
+    Sub,
+    Cmp,
+    And,
+    Or,
+    Xor,
+    Not,
+    Rnd,
+    Rad,
+    Chk,
+    Fnd,
+    Shd,
+    Snd,
+    Rcv,
+}
+
+const OPCODES: [Opcode; 26] = [
+    Opcode::Nop,
+    Opcode::Mov,
+    Opcode::Fwd,
+    Opcode::Rol,
+    Opcode::Ror,
+    Opcode::Sht,
+    Opcode::Rld,
+    Opcode::See,
+    Opcode::Jmp,
+    Opcode::Je,
+    Opcode::Jg,
+    Opcode::Jl,
+    Opcode::Add,
+    Opcode::Sub,
+    Opcode::Cmp,
+    Opcode::And,
+    Opcode::Or,
+    Opcode::Xor,
+    Opcode::Not,
+    Opcode::Rnd,
+    Opcode::Rad,
+    Opcode::Chk,
+    Opcode::Fnd,
+    Opcode::Shd,
+    Opcode::Snd,
+    Opcode::Rcv,
+];
+
+impl Opcode {
+    pub fn from_name(name: &str) -> Option<Opcode> {
+        OPCODES.iter().copied().find(|op| op.name() == name)
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Opcode> {
+        OPCODES.get(byte as usize).copied()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Opcode::Nop => "nop",
+            Opcode::Mov => "mov",
+            Opcode::Fwd => "fwd",
+            Opcode::Rol => "rol",
+            Opcode::Ror => "ror",
+            Opcode::Sht => "sht",
+            Opcode::Rld => "rld",
+            Opcode::See => "see",
+            Opcode::Jmp => "jmp",
+            Opcode::Je => "je",
+            Opcode::Jg => "jg",
+            Opcode::Jl => "jl",
+            Opcode::Add => "add",
+            Opcode::Sub => "sub",
+            Opcode::Cmp => "cmp",
+            Opcode::And => "and",
+            Opcode::Or => "or",
+            Opcode::Xor => "xor",
+            Opcode::Not => "not",
+            Opcode::Rnd => "rnd",
+            Opcode::Rad => "rad",
+            Opcode::Chk => "chk",
+            Opcode::Fnd => "fnd",
+            Opcode::Shd => "shd",
+            Opcode::Snd => "snd",
+            Opcode::Rcv => "rcv",
+        }
+    }
+
+    // Number of operands the instruction takes
+    pub fn operands(&self) -> usize {
+        match self {
+            Opcode::Jmp
+            | Opcode::Je
+            | Opcode::Jg
+            | Opcode::Jl
+            | Opcode::Not
+            | Opcode::Rnd
+            | Opcode::Fnd
+            | Opcode::Snd
+            | Opcode::Rcv => 1,
+
+            Opcode::Mov
+            | Opcode::Add
+            | Opcode::Sub
+            | Opcode::Cmp
+            | Opcode::And
+            | Opcode::Or
+            | Opcode::Xor => 2,
+
+            _ => 0,
+        }
+    }
+
+    // Number of clock cycles the instruction takes to execute
+    pub fn cost(&self) -> i32 {
+        match self {
+            // Motor
+            Opcode::Fwd | Opcode::Rol | Opcode::Ror => 3,
+
+            // Gun
+            Opcode::Sht | Opcode::Rld => 2,
+
+            // Vision and sensors
+            Opcode::See | Opcode::Rad | Opcode::Chk | Opcode::Fnd => 2,
+
+            // Shield
+            Opcode::Shd => 2,
+
+            // Radio
+            Opcode::Snd | Opcode::Rcv => 2,
+
+            // Arithmetic, logic and control flow
+            _ => 1,
+        }
+    }
+
+    // Amount of energy the instruction drains from the robot
+    pub fn energy(&self) -> i32 {
+        match self {
+            Opcode::Fwd => 4,
+            Opcode::Sht => 10,
+            Opcode::See | Opcode::Rad | Opcode::Chk | Opcode::Fnd => 3,
+            Opcode::Shd => 20,
+            _ => 0,
+        }
+    }
+
+    // Instructions that store their result in the destination, which has to be a
+    // register
+    pub fn writes(&self) -> bool {
+        matches!(
+            self,
+            Opcode::Mov
+                | Opcode::Add
+                | Opcode::Sub
+                | Opcode::And
+                | Opcode::Or
+                | Opcode::Xor
+                | Opcode::Not
+                | Opcode::Rnd
+                | Opcode::Rcv
+        )
+    }
+
+    pub fn is_jump(&self) -> bool {
+        matches!(self, Opcode::Jmp | Opcode::Je | Opcode::Jg | Opcode::Jl)
+    }
+
+    // Motor and turret instructions end the tick of the core
+    pub fn is_physical(&self) -> bool {
+        matches!(
+            self,
+            Opcode::Fwd | Opcode::Rol | Opcode::Ror | Opcode::Sht | Opcode::Rld
+        )
+    }
+}
+
+// Registers, numbered in the order they are listed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Register {
+    A,
+    B,
+    C,
+    Ip,
+    Dir,
+    Cx,
+    Cy,
+    Energy,
+    Loaded,
+    Shield,
+    Msg,
+}
+
+const REGISTERS: [Register; 11] = [
+    Register::A,
+    Register::B,
+    Register::C,
+    Register::Ip,
+    Register::Dir,
+    Register::Cx,
+    Register::Cy,
+    Register::Energy,
+    Register::Loaded,
+    Register::Shield,
+    Register::Msg,
+];
+
+impl Register {
+    pub fn from_name(name: &str) -> Option<Register> {
+        REGISTERS.iter().copied().find(|reg| reg.name() == name)
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Register> {
+        REGISTERS.get(byte as usize).copied()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Register::A => "a",
+            Register::B => "b",
+            Register::C => "c",
+            Register::Ip => "ip",
+            Register::Dir => "dir",
+            Register::Cx => "cx",
+            Register::Cy => "cy",
+            Register::Energy => "energy",
+            Register::Loaded => "loaded",
+            Register::Shield => "shield",
+            Register::Msg => "msg",
+        }
+    }
+}
+
+// Constants provided by the engine. Their values depend on the battle, so they are
+// looked up while running, and compiled programs work on fields of any size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Constant {
+    Width,  // Width of the field
+    Height, // Height of the field
+    Id,     // Id of the robot
+}
+
+const CONSTANTS: [Constant; 3] = [Constant::Width, Constant::Height, Constant::Id];
+
+impl Constant {
+    pub fn from_name(name: &str) -> Option<Constant> {
+        CONSTANTS
+            .iter()
+            .copied()
+            .find(|c| c.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Constant> {
+        CONSTANTS.get(byte as usize).copied()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Constant::Width => "WIDTH",
+            Constant::Height => "HEIGHT",
+            Constant::Id => "ID",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    None,
+    Register(Register),
+    Immediate(i16),
+    Constant(Constant),
+}
+
+// Instruction of the bytecode executed by cores. Labels are resolved to immediates
+// and registers to their numbers, so nothing is looked up by name while running.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Op {
+    pub opcode: Opcode,
+    pub dest: Operand,
+    pub src: Operand,
+}
+
+impl Op {
+    pub fn registers(&self) -> Vec<Register> {
+        [self.dest, self.src]
+            .iter()
+            .filter_map(|o| match o {
+                Operand::Register(reg) => Some(*reg),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+// Compiled program, with the line of the source each instruction comes from
+pub struct Bytecode {
+    pub code: Vec<Op>,
+    pub lines: Vec<usize>,
+}
+
+fn operand(value: &Value, labels: &[(String, i16)], line: usize) -> Result<Operand, String> {
+    match value {
+        Value::Immediate(imm) => Ok(Operand::Immediate(*imm)),
+        // The lexer only lets known registers through
+        Value::Register(reg) => match Register::from_name(reg) {
+            Some(reg) => Ok(Operand::Register(reg)),
+            None => Ok(Operand::None),
+        },
+        // Labels can be used as values too
+        Value::Identifier(id) => match labels.iter().rev().find(|(label, _)| label == id) {
+            Some((_, address)) => Ok(Operand::Immediate(*address)),
+            None => match Constant::from_name(id) {
+                Some(constant) => Ok(Operand::Constant(constant)),
+                None => Err(format!(
+                    "Line {}: unknown label or constant {}",
+                    line + 1,
+                    id
+                )),
+            },
+        },
+    }
+}
+
+// Turns the parsed instructions of a program into bytecode
+pub fn assemble(
+    instructions: &[Instruction],
+    labels: &[(String, i16)],
+) -> Result<Bytecode, String> {
+    let mut bytecode = Bytecode {
+        code: vec![],
+        lines: vec![],
+    };
+    for inst in instructions.iter() {
+        let opcode = match Opcode::from_name(inst.opcode()) {
+            Some(opcode) => opcode,
+            None => continue, // Labels
+        };
+        let operands = inst.operands();
+        let mut op = Op {
+            opcode,
+            dest: Operand::None,
+            src: Operand::None,
+        };
+        if let Some(dest) = operands.first() {
+            op.dest = operand(dest, labels, inst.line())?;
+        }
+        if let Some(src) = operands.get(1) {
+            op.src = operand(src, labels, inst.line())?;
+        }
+        if opcode.writes() && !matches!(op.dest, Operand::Register(_)) {
+            return Err(format!(
+                "Line {}: {} needs a register to write to",
+                inst.line() + 1,
+                opcode.name()
+            ));
+        }
+        bytecode.code.push(op);
+        bytecode.lines.push(inst.line());
+    }
+    Ok(bytecode)
+}
+
+// Binary robot files (.hbc) are made of:
+//
+//   magic    "HBC\0"
+//   version  u16
+//   count    u16, number of instructions
+//   code     count instructions of 6 bytes: opcode, kinds of the two operands (low and
+//            high nibble, 0 none, 1 register, 2 immediate, 3 constant) and the two
+//            operands as i16
+//   lines    count u16, line of the source of each instruction
+//
+// All numbers are little endian.
+pub const MAGIC: &[u8; 4] = b"HBC\0";
+pub const VERSION: u16 = 2;
+const OP_SIZE: usize = 6;
+
+fn encode_operand(operand: Operand) -> (u8, i16) {
+    match operand {
+        Operand::None => (0, 0),
+        Operand::Register(reg) => (1, reg as i16),
+        Operand::Immediate(imm) => (2, imm),
+        Operand::Constant(constant) => (3, constant as i16),
+    }
+}
+
+fn decode_operand(kind: u8, value: i16) -> Option<Operand> {
+    match kind {
+        0 => Some(Operand::None),
+        1 if value >= 0 && value <= u8::MAX as i16 => {
+            Register::from_byte(value as u8).map(Operand::Register)
+        }
+        2 => Some(Operand::Immediate(value)),
+        3 if value >= 0 && value <= u8::MAX as i16 => {
+            Constant::from_byte(value as u8).map(Operand::Constant)
+        }
+        _ => None,
+    }
+}
+
+pub fn encode(bytecode: &Bytecode) -> Vec<u8> {
+    let mut out = vec![];
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&(bytecode.code.len() as u16).to_le_bytes());
+    for op in bytecode.code.iter() {
+        let (dest_kind, dest) = encode_operand(op.dest);
+        let (src_kind, src) = encode_operand(op.src);
+        out.push(op.opcode as u8);
+        out.push(dest_kind | src_kind << 4);
+        out.extend_from_slice(&dest.to_le_bytes());
+        out.extend_from_slice(&src.to_le_bytes());
+    }
+    for line in bytecode.lines.iter() {
+        let line = std::cmp::min(*line, u16::MAX as usize) as u16;
+        out.extend_from_slice(&line.to_le_bytes());
+    }
+    out
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+// Reads a binary robot file, checking every instruction is one the core can execute
+pub fn decode(bytes: &[u8]) -> Result<Bytecode, String> {
+    if bytes.len() < 8 || &bytes[..4] != MAGIC {
+        return Err(String::from("Not a compiled robot program"));
+    }
+    let version = read_u16(bytes, 4);
+    if version != VERSION {
+        return Err(format!(
+            "Unsupported bytecode version {}, expected {}",
+            version, VERSION
+        ));
+    }
+    let count = read_u16(bytes, 6) as usize;
+    let lines_start = 8 + count * OP_SIZE;
+    if bytes.len() != lines_start + count * 2 {
+        return Err(format!(
+            "Compiled program should be {} bytes long, found {}",
+            lines_start + count * 2,
+            bytes.len()
+        ));
+    }
+
+    let mut bytecode = Bytecode {
+        code: vec![],
+        lines: vec![],
+    };
+    for i in 0..count {
+        let at = 8 + i * OP_SIZE;
+        let opcode = match Opcode::from_byte(bytes[at]) {
+            Some(opcode) => opcode,
+            None => return Err(format!("Instruction {}: unknown opcode {}", i, bytes[at])),
+        };
+        let dest = decode_operand(bytes[at + 1] & 0xf, read_u16(bytes, at + 2) as i16);
+        let src = decode_operand(bytes[at + 1] >> 4, read_u16(bytes, at + 4) as i16);
+        let (dest, src) = match (dest, src) {
+            (Some(dest), Some(src)) => (dest, src),
+            _ => return Err(format!("Instruction {}: invalid operand", i)),
+        };
+
+        let found = match (dest, src) {
+            (Operand::None, Operand::None) => Some(0),
+            (Operand::None, _) => None,
+            (_, Operand::None) => Some(1),
+            _ => Some(2),
+        };
+        if found != Some(opcode.operands()) {
+            return Err(format!(
+                "Instruction {}: {} takes {} operands",
+                i,
+                opcode.name(),
+                opcode.operands()
+            ));
+        }
+        if opcode.writes() && !matches!(dest, Operand::Register(_)) {
+            return Err(format!(
+                "Instruction {}: {} needs a register to write to",
+                i,
+                opcode.name()
+            ));
+        }
+
+        bytecode.code.push(Op { opcode, dest, src });
+        bytecode
+            .lines
+            .push(read_u16(bytes, lines_start + i * 2) as usize);
+    }
+    Ok(bytecode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn try_compile(source: &str) -> Result<Bytecode, String> {
+        let (instructions, labels, _) = parse_file(Path::new(""), source)?;
+        assemble(&instructions, &labels)
+    }
+
+    fn compile(source: &str) -> Bytecode {
+        try_compile(source).unwrap()
+    }
+
+    #[test]
+    fn numbers_match_the_tables() {
+        for (i, op) in OPCODES.iter().enumerate() {
+            assert_eq!(*op as usize, i);
+            assert_eq!(Opcode::from_name(op.name()), Some(*op));
+        }
+        for (i, reg) in REGISTERS.iter().enumerate() {
+            assert_eq!(*reg as usize, i);
+            assert_eq!(Register::from_name(reg.name()), Some(*reg));
+        }
+        for (i, constant) in CONSTANTS.iter().enumerate() {
+            assert_eq!(*constant as usize, i);
+            assert_eq!(Constant::from_name(constant.name()), Some(*constant));
+        }
+    }
+
+    #[test]
+    fn reports_costs_and_energy() {
+        assert_eq!(Opcode::Fwd.cost(), 3);
+        assert_eq!(Opcode::Sht.cost(), 2);
+        assert_eq!(Opcode::Rad.cost(), 2);
+        assert_eq!(Opcode::Mov.cost(), 1);
+        assert_eq!(Opcode::Fwd.energy(), 4);
+        assert_eq!(Opcode::Sht.energy(), 10);
+        assert_eq!(Opcode::Shd.energy(), 20);
+        assert_eq!(Opcode::Add.energy(), 0);
+    }
+
+    #[test]
+    fn assembles_instructions() {
+        let bytecode = compile("start:\n  mov a, 5\n  add a, b\n  fwd\n  jmp start");
+        assert_eq!(
+            bytecode.code,
+            vec![
+                Op {
+                    opcode: Opcode::Mov,
+                    dest: Operand::Register(Register::A),
+                    src: Operand::Immediate(5),
+                },
+                Op {
+                    opcode: Opcode::Add,
+                    dest: Operand::Register(Register::A),
+                    src: Operand::Register(Register::B),
+                },
+                Op {
+                    opcode: Opcode::Fwd,
+                    dest: Operand::None,
+                    src: Operand::None,
+                },
+                Op {
+                    opcode: Opcode::Jmp,
+                    dest: Operand::Immediate(0),
+                    src: Operand::None,
+                },
+            ]
+        );
+        assert_eq!(bytecode.lines, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn labels_can_be_moved_into_registers() {
+        let bytecode = compile("fwd\nhere:\nmov a, here");
+        assert_eq!(bytecode.code[1].src, Operand::Immediate(1));
+    }
+
+    #[test]
+    fn rejects_unknown_identifiers() {
+        for (source, error) in [
+            (
+                "fwd\nmov b, widht",
+                "Line 2: unknown label or constant widht",
+            ),
+            ("jmp nowhere", "Line 1: unknown label or constant nowhere"),
+        ] {
+            assert_eq!(try_compile(source).err(), Some(String::from(error)));
+        }
+    }
+
+    #[test]
+    fn predefined_constants_are_kept_for_the_engine() {
+        let bytecode = compile("mov a, WIDTH\ncmp b, height\nsnd id");
+        assert_eq!(bytecode.code[0].src, Operand::Constant(Constant::Width));
+        assert_eq!(bytecode.code[1].src, Operand::Constant(Constant::Height));
+        assert_eq!(bytecode.code[2].dest, Operand::Constant(Constant::Id));
+    }
+
+    #[test]
+    fn encodes_and_decodes() {
+        let bytecode =
+            compile("loop:\n  rad\n  mov c, 32767\n  snd dir\n  sub c, HEIGHT\n  jg loop");
+        let bytes = encode(&bytecode);
+        assert_eq!(bytes.len(), 8 + 5 * 8);
+        let decoded = decode(&bytes).unwrap();
+        assert_eq!(decoded.code, bytecode.code);
+        assert_eq!(decoded.lines, bytecode.lines);
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        let bytes = encode(&compile("mov a, 1\nfwd"));
+        assert!(decode(b"").is_err());
+        assert!(decode(b"hasm source").is_err());
+        assert!(decode(&bytes[..bytes.len() - 1]).is_err());
+
+        let mut version = bytes.clone();
+        version[4] = 1;
+        assert!(decode(&version).is_err());
+
+        let mut opcode = bytes.clone();
+        opcode[8] = 200;
+        assert!(decode(&opcode).is_err());
+
+        // Register 99 doesn't exist
+        let mut register = bytes.clone();
+        register[10] = 99;
+        assert!(decode(&register).is_err());
+
+        // fwd with an operand
+        let mut operands = bytes.clone();
+        operands[15] = 2;
+        assert!(decode(&operands).is_err());
+
+        // mov writing to an immediate
+        let mut dest = bytes.clone();
+        dest[9] = 0x22;
+        assert_eq!(
+            decode(&dest).err(),
+            Some(String::from(
+                "Instruction 0: mov needs a register to write to"
+            ))
+        );
+    }
+
+    #[test]
+    fn rejects_writes_to_values() {
+        for source in ["mov 1, a", "fwd\nadd WIDTH, 1", "rcv 3"] {
+            assert!(try_compile(source).is_err(), "{}", source);
+        }
+        assert_eq!(
+            try_compile("fwd\nnot 7").err(),
+            Some(String::from("Line 2: not needs a register to write to"))
+        );
+    }
+}
@@ -0,0 +1,404 @@
+use crate::bytecode::*;
+use crate::config::*;
+use crate::disasm::*;
+use crate::fmt::*;
+use crate::json::*;
+use crate::ladder::*;
+use crate::lint::*;
+use crate::report::*;
+use crate::robot::*;
+use crate::scenario::*;
+use crate::tournament::*;
+use crate::Field;
+
+use std::collections::HashMap;
+use std::path::Path;
+
+const USAGE: &str = "Usage:
+  hbot                                Opens the battle window
+  hbot run CONFIG [OPTIONS]           Runs a match without opening the window
+      --seed N                        Overrides the seed of the match
+      --json FILE                     Writes the results of the match as JSON (- for stdout)
+      --ratings FILE                  Ratings file to update with the result of a match
+                                      between two robots
+  hbot tournament DIR MAP [OPTIONS]   Runs a tournament between the programs in DIR
+      --mode round-robin|bracket      How contenders are paired up (default round-robin)
+      --seeds N                       Matches on each side of every pairing (default 3)
+      --ratings FILE                  Ratings file updated after every match (default ratings.txt)
+      --json FILE                     Writes the results of all matches as JSON (- for stdout)
+  hbot build PROGRAM [--out FILE]     Compiles a program to bytecode (default PROGRAM.hbc)
+  hbot disasm PROGRAM                 Prints a compiled program as source
+  hbot fmt [--check] [PATH...]        Formats the programs in the given files or directories
+                                      (default the current directory)
+  hbot lint [PATH...]                 Looks for mistakes in the programs in the given files or
+                                      directories (default the current directory)
+  hbot test [PATH...]                 Runs the scenarios in the given files or directories
+                                      (default scenarios)
+  hbot ladder [--ratings FILE]        Prints the leaderboard
+  hbot ladder history [NAME] [--ratings FILE]
+                                      Prints the rating changes of all programs or one of them";
+
+// Runs a command given on the command line. Commands never open the window.
+pub fn run(args: &[String]) -> Result<(), String> {
+    match args[0].as_str() {
+        "run" => run_match(&args[1..]),
+        "tournament" => tournament(&args[1..]),
+        "build" => build(&args[1..]),
+        "disasm" => disasm(&args[1..]),
+        "fmt" => fmt(&args[1..]),
+        "lint" => lint_programs(&args[1..]),
+        "test" => test(&args[1..]),
+        "ladder" => ladder(&args[1..]),
+        "help" | "--help" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(format!("unknown command {}\n{}", args[0], USAGE)),
+    }
+}
+
+// Splits the arguments of a command into positional arguments and options. Every
+// option is followed by its value.
+fn parse_args(args: &[String]) -> Result<(Vec<&str>, HashMap<&str, &str>), String> {
+    let mut positional = vec![];
+    let mut options = HashMap::new();
+    let mut i = 0;
+    while i < args.len() {
+        if let Some(name) = args[i].strip_prefix("--") {
+            match args.get(i + 1) {
+                Some(value) => options.insert(name, value.as_str()),
+                None => return Err(format!("--{} needs a value", name)),
+            };
+            i += 2;
+        } else {
+            positional.push(args[i].as_str());
+            i += 1;
+        }
+    }
+    Ok((positional, options))
+}
+
+fn run_match(args: &[String]) -> Result<(), String> {
+    let (positional, options) = parse_args(args)?;
+    if positional.len() != 1 {
+        return Err(format!("run needs a match configuration\n{}", USAGE));
+    }
+
+    let mut config = MatchConfig::load(positional[0])?;
+    if let Some(seed) = options.get("seed") {
+        config.seed = match seed.parse::<u64>() {
+            Ok(seed) => seed,
+            Err(_) => return Err(format!("{} is not a valid seed", seed)),
+        };
+    }
+
+    let mut battle = config.start()?;
+    let field = Field {
+        x: 0,
+        y: 0,
+        width: config.width,
+        height: config.height,
+    };
+    let winner = run_battle(&mut battle, &field, config.max_ticks);
+    if let Some(ratings) = options.get("ratings") {
+        rate_match(&config, winner, ratings)?;
+    }
+
+    // Keep the standard output clean when the JSON goes there
+    let json = options.get("json");
+    if json != Some(&"-") {
+        match winner {
+            Some(team) => {
+                let names: Vec<&str> = battle.robots.iter().map(|r| r.name.as_str()).collect();
+                println!(
+                    "Team {} wins after {} ticks: {}",
+                    team,
+                    battle.tick,
+                    names.join(", ")
+                );
+            }
+            None => println!("Draw after {} ticks", battle.tick),
+        }
+    }
+    if let Some(path) = json {
+        write_json(&match_report(&config, &battle), path)?;
+    }
+    Ok(())
+}
+
+// Updates the ratings of the two robots of a match with its result
+fn rate_match(config: &MatchConfig, winner: Option<i32>, ratings: &str) -> Result<(), String> {
+    let robots = &config.robots;
+    if robots.len() != 2 || robots[0].team == robots[1].team {
+        return Err(String::from(
+            "--ratings needs a match between two robots on different teams",
+        ));
+    }
+    let outcome = match winner {
+        Some(team) if team == robots[0].team => Outcome::Win,
+        Some(_) => Outcome::Loss,
+        None => Outcome::Draw,
+    };
+    let mut hashes = vec![];
+    for robot in robots.iter() {
+        let program = read_program(&robot.program)?;
+        match program_hash(&program) {
+            Ok(hash) => hashes.push(hash),
+            Err(e) => return Err(format!("{}: {}", robot.program, e)),
+        }
+    }
+    let mut ladder = Ladder::load(ratings)?;
+    ladder.record(
+        (hashes[0], &robots[0].name),
+        (hashes[1], &robots[1].name),
+        outcome,
+    )
+}
+
+fn build(args: &[String]) -> Result<(), String> {
+    let (positional, options) = parse_args(args)?;
+    if positional.len() != 1 {
+        return Err(format!("build needs a program\n{}", USAGE));
+    }
+
+    let path = positional[0];
+    let (path_buf, source) = match read_program(path)? {
+        ProgramFile::Source(path, source) => (path, source),
+        ProgramFile::Bytecode(_) => return Err(format!("{} is already compiled", path)),
+    };
+    let mut core = Core {
+        source,
+        path: path_buf,
+        ..Default::default()
+    };
+    if let Err(e) = core.compile() {
+        return Err(format!("{}: {}", path, e));
+    }
+
+    let out = match options.get("out") {
+        Some(out) => out.to_string(),
+        None => Path::new(path)
+            .with_extension("hbc")
+            .to_string_lossy()
+            .into_owned(),
+    };
+    let bytecode = Bytecode {
+        code: core.code,
+        lines: core.lines,
+    };
+    if let Err(e) = std::fs::write(&out, encode(&bytecode)) {
+        return Err(format!("Couldn't write {}: {}", out, e));
+    }
+    println!("Compiled {} instructions to {}", bytecode.code.len(), out);
+    Ok(())
+}
+
+fn disasm(args: &[String]) -> Result<(), String> {
+    let (positional, _) = parse_args(args)?;
+    if positional.len() != 1 {
+        return Err(format!("disasm needs a program\n{}", USAGE));
+    }
+
+    // Source programs are compiled first, showing how their labels are resolved
+    let path = positional[0];
+    let mut core = Core::default();
+    let result = match read_program(path)? {
+        ProgramFile::Source(path, source) => {
+            core.path = path;
+            core.source = source;
+            core.compile()
+        }
+        ProgramFile::Bytecode(bytes) => core.load_bytecode(&bytes),
+    };
+    if let Err(e) = result {
+        return Err(format!("{}: {}", path, e));
+    }
+    print!("{}", disassemble(&core.code).0);
+    Ok(())
+}
+
+fn tournament(args: &[String]) -> Result<(), String> {
+    let (positional, options) = parse_args(args)?;
+    if positional.len() != 2 {
+        return Err(format!("tournament needs a directory and a map\n{}", USAGE));
+    }
+
+    let mode = match options.get("mode") {
+        Some(name) => match TournamentMode::from_name(name) {
+            Some(mode) => mode,
+            None => return Err(format!("unknown tournament mode {}", name)),
+        },
+        None => TournamentMode::RoundRobin,
+    };
+    let seeds = match options.get("seeds") {
+        Some(n) => match n.parse::<u32>() {
+            Ok(n) if n > 0 => n,
+            _ => return Err(format!("{} is not a valid number of seeds", n)),
+        },
+        None => 3,
+    };
+
+    let ratings = options.get("ratings").unwrap_or(&"ratings.txt");
+    let mut ladder = Ladder::load(ratings)?;
+
+    // Keep the standard output clean when the JSON goes there
+    let json = options.get("json");
+    let quiet = json == Some(&"-");
+
+    let mut tournament = Tournament::new(positional[0], positional[1], mode, seeds)?;
+    tournament.quiet = quiet;
+    for e in tournament.rejected.iter() {
+        eprintln!("Error: {}", e);
+    }
+
+    let mut reports = vec![];
+    let standings = tournament.run(&mut ladder, &mut reports)?;
+
+    if !quiet {
+        print_standings(&standings);
+    }
+    if let Some(path) = json {
+        write_json(&Json::Array(reports), path)?;
+    }
+    Ok(())
+}
+
+fn ladder(args: &[String]) -> Result<(), String> {
+    let (positional, options) = parse_args(args)?;
+    let ratings = options.get("ratings").unwrap_or(&"ratings.txt");
+    let ladder = Ladder::load(ratings)?;
+
+    match positional.as_slice() {
+        [] => ladder.print_leaderboard(),
+        ["history"] => ladder.print_history(None),
+        ["history", name] => ladder.print_history(Some(name)),
+        _ => return Err(format!("invalid arguments for ladder\n{}", USAGE)),
+    }
+    Ok(())
+}
+
+// Files of the given paths, looking for files with the extension inside directories
+fn files_with_extension(paths: &[&str], extension: &str) -> Result<Vec<String>, String> {
+    let mut files = vec![];
+    for path in paths {
+        if !Path::new(path).is_dir() {
+            files.push(path.to_string());
+            continue;
+        }
+
+        let entries = match std::fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(e) => return Err(format!("Couldn't read {}: {}", path, e)),
+        };
+        let mut found: Vec<String> = entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.extension().is_some_and(|ext| ext == extension))
+            .map(|p| p.to_string_lossy().into_owned())
+            .collect();
+        found.sort();
+        files.extend(found);
+    }
+    Ok(files)
+}
+
+fn fmt(args: &[String]) -> Result<(), String> {
+    // --check is the only option without a value
+    let check = args.iter().any(|a| a == "--check");
+    let args: Vec<String> = args.iter().filter(|a| *a != "--check").cloned().collect();
+    let (mut positional, _) = parse_args(&args)?;
+    if positional.is_empty() {
+        positional.push(".");
+    }
+
+    let mut unformatted = 0;
+    for file in files_with_extension(&positional, "hasm")? {
+        let source = match std::fs::read_to_string(&file) {
+            Ok(source) => source,
+            Err(e) => return Err(format!("Couldn't read {}: {}", file, e)),
+        };
+        let formatted = match format_source(&source) {
+            Ok(formatted) => formatted,
+            Err(e) => return Err(format!("{}: {}", file, e)),
+        };
+        if formatted == source {
+            continue;
+        }
+
+        if check {
+            println!("{} isn't formatted", file);
+            unformatted += 1;
+        } else if let Err(e) = std::fs::write(&file, formatted) {
+            return Err(format!("Couldn't write {}: {}", file, e));
+        } else {
+            println!("Formatted {}", file);
+        }
+    }
+
+    if unformatted > 0 {
+        return Err(format!("{} programs aren't formatted", unformatted));
+    }
+    Ok(())
+}
+
+fn lint_programs(args: &[String]) -> Result<(), String> {
+    let (mut positional, _) = parse_args(args)?;
+    if positional.is_empty() {
+        positional.push(".");
+    }
+
+    let mut count = 0;
+    for file in files_with_extension(&positional, "hasm")? {
+        let (path, source) = match read_program(&file)? {
+            ProgramFile::Source(path, source) => (path, source),
+            ProgramFile::Bytecode(_) => continue,
+        };
+        let warnings = match lint(&path, &source) {
+            Ok(warnings) => warnings,
+            Err(e) => return Err(format!("{}: {}", file, e)),
+        };
+        for warning in warnings.iter() {
+            println!("{}: {}", file, warning);
+        }
+        count += warnings.len();
+    }
+
+    if count > 0 {
+        return Err(format!("{} warnings", count));
+    }
+    Ok(())
+}
+
+fn test(args: &[String]) -> Result<(), String> {
+    let (mut positional, _) = parse_args(args)?;
+    if positional.is_empty() {
+        positional.push("scenarios");
+    }
+
+    let mut failed = 0;
+    let files = files_with_extension(&positional, "scn")?;
+    for file in files.iter() {
+        let result = Scenario::load(file).and_then(|scenario| scenario.run());
+        match result {
+            Ok(failures) if failures.is_empty() => println!("PASS {}", file),
+            Ok(failures) => {
+                println!("FAIL {}", file);
+                for failure in failures {
+                    println!("  {}", failure);
+                }
+                failed += 1;
+            }
+            Err(e) => {
+                println!("FAIL {}", file);
+                println!("  Error: {}", e);
+                failed += 1;
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", files.len() - failed, failed);
+    if failed > 0 {
+        return Err(format!("{} scenarios failed", failed));
+    }
+    Ok(())
+}
@@ -0,0 +1,115 @@
+// Instructions and registers every core has, regardless of its components
+pub const CORE_INSTRUCTIONS: &[&str] = &[
+    "nop", "mov", "jmp", "je", "jg", "jl", "add", "sub", "cmp", "and", "or", "xor", "not", "rnd",
+];
+pub const CORE_REGISTERS: &[&str] = &["a", "b", "c", "ip", "energy"];
+
+// A piece of hardware mounted on a robot. Each component makes a set of instructions
+// and registers available to the robot's program.
+pub trait Component {
+    fn name(&self) -> &'static str;
+    fn instructions(&self) -> &'static [&'static str];
+    fn registers(&self) -> &'static [&'static str];
+}
+
+pub struct Motor;
+
+impl Component for Motor {
+    fn name(&self) -> &'static str {
+        "motor"
+    }
+
+    fn instructions(&self) -> &'static [&'static str] {
+        &["fwd", "rol", "ror"]
+    }
+
+    fn registers(&self) -> &'static [&'static str] {
+        &[]
+    }
+}
+
+pub struct Turret;
+
+impl Component for Turret {
+    fn name(&self) -> &'static str {
+        "turret"
+    }
+
+    fn instructions(&self) -> &'static [&'static str] {
+        &["sht", "rld"]
+    }
+
+    fn registers(&self) -> &'static [&'static str] {
+        &["loaded"]
+    }
+}
+
+pub struct Sensors;
+
+impl Component for Sensors {
+    fn name(&self) -> &'static str {
+        "sensors"
+    }
+
+    fn instructions(&self) -> &'static [&'static str] {
+        &["see", "rad", "chk", "fnd"]
+    }
+
+    fn registers(&self) -> &'static [&'static str] {
+        &["dir", "cx", "cy"]
+    }
+}
+
+pub struct Shield;
+
+impl Component for Shield {
+    fn name(&self) -> &'static str {
+        "shield"
+    }
+
+    fn instructions(&self) -> &'static [&'static str] {
+        &["shd"]
+    }
+
+    fn registers(&self) -> &'static [&'static str] {
+        &["shield"]
+    }
+}
+
+pub struct Radio;
+
+impl Component for Radio {
+    fn name(&self) -> &'static str {
+        "radio"
+    }
+
+    fn instructions(&self) -> &'static [&'static str] {
+        &["snd", "rcv"]
+    }
+
+    fn registers(&self) -> &'static [&'static str] {
+        &["msg"]
+    }
+}
+
+pub fn component_from_name(name: &str) -> Option<Box<dyn Component>> {
+    match name {
+        "motor" => Some(Box::new(Motor)),
+        "turret" => Some(Box::new(Turret)),
+        "sensors" => Some(Box::new(Sensors)),
+        "shield" => Some(Box::new(Shield)),
+        "radio" => Some(Box::new(Radio)),
+        _ => None,
+    }
+}
+
+// Loadout of robots that don't specify their components
+pub fn default_loadout() -> Vec<Box<dyn Component>> {
+    vec![
+        Box::new(Motor),
+        Box::new(Turret),
+        Box::new(Sensors),
+        Box::new(Shield),
+        Box::new(Radio),
+    ]
+}
@@ -0,0 +1,586 @@
+use super::*;
+
+use crate::bytecode::Register;
+use crate::component::*;
+use crate::pickup::*;
+use std::path::Path;
+
+// Match configuration, loaded from a file in the following format:
+//
+//   # Comment
+//   robot Robot name
+//     program space_invader.hasm
+//     position 0 9
+//     direction 0 -1
+//     team -1
+//     color 251 97 7
+//     aoi -1 0 -1 -1 0 -1
+//     components motor turret sensors
+//     weapon reload 3 damage 10 range 10 magazine 1
+//     armor 4 2 0
+//     health 100
+//     energy 100
+//     bullets 100
+//     registers a 5 b -3
+//
+// Every `robot` line starts the description of a new robot. All keys are optional,
+// but a robot needs a program to take part in a match. Maps used by tournaments leave
+// the programs out. The following keys describe the match itself and can appear
+// anywhere in the file:
+//
+//   field 10 10
+//   max_ticks 1000
+//   seed 42
+//   shuffle_spawns
+//   pickup health 4 4
+//   pickup_rate 20
+#[derive(Clone)]
+pub struct MatchConfig {
+    pub robots: Vec<RobotConfig>,
+    pub width: i32,
+    pub height: i32,
+    pub max_ticks: u32, // Length of headless matches before they end in a draw
+    pub seed: u64,
+    pub shuffle_spawns: bool,
+    pub pickups: Vec<Pickup>,
+    pub pickup_rate: u32,
+}
+
+#[derive(Clone)]
+pub struct RobotConfig {
+    pub name: String,
+    pub program: String,
+    pub x: i32,
+    pub y: i32,
+    pub direction: (i32, i32),
+    pub team: i32,
+    pub color: Color,
+    pub aoi: Vec<(i32, i32)>,
+    pub components: Option<Vec<String>>, // None for the default loadout
+    pub weapon: Weapon,
+    pub armor: Armor,
+    pub health: Option<i32>, // None for the defaults of the robot
+    pub energy: Option<i32>,
+    pub bullets: Option<i32>,
+    pub registers: Vec<(Register, i16)>, // Initial values of general purpose registers
+}
+
+fn parse_numbers(args: &[&str], line: usize) -> Result<Vec<i32>, String> {
+    let mut out = vec![];
+    for arg in args {
+        match arg.parse::<i32>() {
+            Ok(n) => out.push(n),
+            Err(_) => return Err(format!("Line {}: {} is not a number", line, arg)),
+        }
+    }
+    Ok(out)
+}
+
+fn expect_args(args: &[&str], count: usize, line: usize) -> Result<(), String> {
+    if args.len() != count {
+        let plural = if count == 1 { "" } else { "s" };
+        return Err(format!(
+            "Line {}: expected {} argument{}, found {}",
+            line,
+            count,
+            plural,
+            args.len()
+        ));
+    }
+    Ok(())
+}
+
+fn expect_numbers(args: &[&str], count: usize, line: usize) -> Result<Vec<i32>, String> {
+    expect_args(args, count, line)?;
+    parse_numbers(args, line)
+}
+
+impl MatchConfig {
+    pub fn load(path: &str) -> Result<MatchConfig, String> {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) => return Err(format!("Couldn't read {}: {}", path, e)),
+        };
+
+        let mut config = MatchConfig::parse(&source)?;
+        config.resolve_programs(path);
+        Ok(config)
+    }
+
+    // Makes the paths of the programs relative to the configuration file
+    pub fn resolve_programs(&mut self, path: &str) {
+        if let Some(dir) = Path::new(path).parent() {
+            for robot in self.robots.iter_mut() {
+                if !robot.program.is_empty() {
+                    robot.program = dir.join(&robot.program).to_string_lossy().into_owned();
+                }
+            }
+        }
+    }
+
+    pub fn parse(source: &str) -> Result<MatchConfig, String> {
+        let mut config = MatchConfig {
+            robots: vec![],
+            width: 10,
+            height: 10,
+            max_ticks: 1000,
+            seed: 0,
+            shuffle_spawns: false,
+            pickups: vec![],
+            pickup_rate: 0,
+        };
+
+        for (i, text) in source.lines().enumerate() {
+            let line = i + 1;
+            let text = text.split('#').next().unwrap_or("");
+            let words: Vec<&str> = text.split_whitespace().collect();
+            if words.is_empty() {
+                continue;
+            }
+            let (key, args) = (words[0], &words[1..]);
+
+            match key {
+                "field" => {
+                    let n = expect_numbers(args, 2, line)?;
+                    if n[0] <= 0 || n[1] <= 0 {
+                        return Err(format!("Line {}: invalid field size", line));
+                    }
+                    config.width = n[0];
+                    config.height = n[1];
+                    continue;
+                }
+                "max_ticks" => {
+                    config.max_ticks = expect_numbers(args, 1, line)?[0].max(0) as u32;
+                    continue;
+                }
+                "seed" => {
+                    expect_args(args, 1, line)?;
+                    config.seed = match args[0].parse::<u64>() {
+                        Ok(seed) => seed,
+                        Err(_) => {
+                            return Err(format!("Line {}: {} is not a valid seed", line, args[0]))
+                        }
+                    };
+                    continue;
+                }
+                "shuffle_spawns" => {
+                    config.shuffle_spawns = true;
+                    continue;
+                }
+                "pickup" => {
+                    if args.len() != 3 {
+                        return Err(format!("Line {}: expected 3 arguments", line));
+                    }
+                    let kind = match PickupKind::from_name(args[0]) {
+                        Some(kind) => kind,
+                        None => return Err(format!("Line {}: unknown pickup {}", line, args[0])),
+                    };
+                    let n = parse_numbers(&args[1..], line)?;
+                    config.pickups.push(Pickup {
+                        x: n[0],
+                        y: n[1],
+                        kind,
+                    });
+                    continue;
+                }
+                "pickup_rate" => {
+                    config.pickup_rate = expect_numbers(args, 1, line)?[0].max(0) as u32;
+                    continue;
+                }
+                _ => (),
+            }
+
+            if key == "robot" {
+                config.robots.push(RobotConfig {
+                    name: args.join(" "),
+                    program: String::from(""),
+                    x: 0,
+                    y: 0,
+                    direction: (0, 1),
+                    team: 1,
+                    color: Color::RED,
+                    aoi: vec![],
+                    components: None,
+                    weapon: Weapon::default(),
+                    armor: Armor::default(),
+                    health: None,
+                    energy: None,
+                    bullets: None,
+                    registers: vec![],
+                });
+                continue;
+            }
+
+            let robot = match config.robots.last_mut() {
+                Some(robot) => robot,
+                None => return Err(format!("Line {}: {} outside of a robot", line, key)),
+            };
+
+            match key {
+                "program" => robot.program = args.join(" "),
+                "position" => {
+                    let n = expect_numbers(args, 2, line)?;
+                    robot.x = n[0];
+                    robot.y = n[1];
+                }
+                "direction" => {
+                    let n = expect_numbers(args, 2, line)?;
+                    if n[0].abs() + n[1].abs() != 1 {
+                        return Err(format!("Line {}: invalid direction", line));
+                    }
+                    robot.direction = (n[0], n[1]);
+                }
+                "team" => robot.team = expect_numbers(args, 1, line)?[0],
+                "color" => {
+                    let n = expect_numbers(args, 3, line)?;
+                    robot.color = Color::new(n[0] as u8, n[1] as u8, n[2] as u8, 255);
+                }
+                "aoi" => {
+                    let n = parse_numbers(args, line)?;
+                    if n.len() % 2 != 0 {
+                        return Err(format!("Line {}: aoi needs pairs of coordinates", line));
+                    }
+                    robot.aoi = n.chunks(2).map(|c| (c[0], c[1])).collect();
+                }
+                "components" => {
+                    for name in args {
+                        if component_from_name(name).is_none() {
+                            return Err(format!("Line {}: unknown component {}", line, name));
+                        }
+                    }
+                    robot.components = Some(args.iter().map(|s| s.to_string()).collect());
+                }
+                "weapon" => {
+                    if args.len() % 2 != 0 {
+                        return Err(format!(
+                            "Line {}: weapon needs pairs of stat and value",
+                            line
+                        ));
+                    }
+                    for pair in args.chunks(2) {
+                        let value = parse_numbers(&pair[1..], line)?[0];
+                        match pair[0] {
+                            "reload" => robot.weapon.reload_ticks = value,
+                            "damage" => robot.weapon.damage = value,
+                            "range" => robot.weapon.range = value,
+                            "magazine" => robot.weapon.magazine_size = value,
+                            _ => return Err(format!("Line {}: unknown stat {}", line, pair[0])),
+                        }
+                    }
+                }
+                "armor" => {
+                    let n = expect_numbers(args, 3, line)?;
+                    robot.armor = Armor {
+                        front: n[0],
+                        side: n[1],
+                        rear: n[2],
+                    };
+                }
+                "health" => robot.health = Some(expect_numbers(args, 1, line)?[0]),
+                "energy" => robot.energy = Some(expect_numbers(args, 1, line)?[0]),
+                "bullets" => robot.bullets = Some(expect_numbers(args, 1, line)?[0]),
+                "registers" => {
+                    if args.len() % 2 != 0 {
+                        return Err(format!(
+                            "Line {}: registers needs pairs of register and value",
+                            line
+                        ));
+                    }
+                    for pair in args.chunks(2) {
+                        let reg = match pair[0] {
+                            "a" => Register::A,
+                            "b" => Register::B,
+                            "c" => Register::C,
+                            _ => {
+                                return Err(format!(
+                                    "Line {}: {} is not a general purpose register",
+                                    line, pair[0]
+                                ))
+                            }
+                        };
+                        let value = match pair[1].parse::<i16>() {
+                            Ok(value) => value,
+                            Err(_) => {
+                                return Err(format!("Line {}: {} is not a number", line, pair[1]))
+                            }
+                        };
+                        robot.registers.push((reg, value));
+                    }
+                }
+                _ => return Err(format!("Line {}: unknown key {}", line, key)),
+            }
+        }
+
+        Ok(config)
+    }
+
+    // Spawns the robots and sets up the battle
+    pub fn start(&self) -> Result<Battle, String> {
+        let mut robots = vec![];
+        for robot in self.robots.iter() {
+            robots.push(robot.spawn()?);
+        }
+
+        let mut battle = Battle::new(robots, self.seed);
+        if self.shuffle_spawns {
+            battle.shuffle_spawns();
+        }
+        battle.pickups = self.pickups.clone();
+        battle.pickup_rate = self.pickup_rate;
+        Ok(battle)
+    }
+}
+
+impl RobotConfig {
+    // Creates the robot and compiles its program
+    pub fn spawn(&self) -> Result<Robot, String> {
+        if self.program.is_empty() {
+            return Err(format!("Robot {} has no program", self.name));
+        }
+        let program = read_program(&self.program)?;
+
+        let components = match &self.components {
+            Some(names) => names
+                .iter()
+                .filter_map(|name| component_from_name(name))
+                .collect(),
+            None => default_loadout(),
+        };
+
+        let mut robot = Robot {
+            x: self.x,
+            y: self.y,
+            direction: self.direction,
+            team: self.team,
+            color: self.color,
+            name: self.name.clone(),
+            aoi: self.aoi.clone(),
+            components,
+            weapon: self.weapon.clone(),
+            magazine: self.weapon.magazine_size,
+            armor: self.armor.clone(),
+            ..Default::default()
+        };
+        // The robot starts full, so crates and recharging can't go past these values
+        if let Some(health) = self.health {
+            robot.max_health = health;
+            robot.health = health;
+        }
+        if let Some(energy) = self.energy {
+            robot.max_energy = energy;
+            robot.energy = energy;
+        }
+        if let Some(bullets) = self.bullets {
+            robot.bullets = bullets;
+        }
+        for (reg, value) in self.registers.iter() {
+            robot.set_register(*reg, *value);
+        }
+        robot.load_program(program)?;
+        Ok(robot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes the programs of a test to a directory of its own, returning the path of
+    // the configuration
+    fn write_programs(test: &str, programs: &[(&str, &str)]) -> String {
+        let dir = std::env::temp_dir().join(format!("hbot-config-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, source) in programs {
+            fs::write(dir.join(name), source).unwrap();
+        }
+        dir.join("match.cfg").to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn parses_matches() {
+        let config = MatchConfig::parse(
+            "# Duel
+field 12 8
+max_ticks 300
+seed 42
+shuffle_spawns
+pickup ammo 3 4
+pickup_rate 20
+
+robot Space invader
+  program space_invader.hasm # The example
+  position 0 7
+  direction 0 -1
+  team 2
+  color 251 97 7
+  aoi -1 0 1 0
+  components motor turret
+  weapon reload 2 damage 15 range 5 magazine 3
+  armor 4 2 0
+  bullets 50
+  registers a 5 b -3
+
+robot Idle
+",
+        )
+        .unwrap();
+
+        assert_eq!((config.width, config.height), (12, 8));
+        assert_eq!(config.max_ticks, 300);
+        assert_eq!(config.seed, 42);
+        assert!(config.shuffle_spawns);
+        assert_eq!(config.pickups.len(), 1);
+        assert_eq!(config.pickups[0].kind, PickupKind::Ammo);
+        assert_eq!((config.pickups[0].x, config.pickups[0].y), (3, 4));
+        assert_eq!(config.pickup_rate, 20);
+
+        assert_eq!(config.robots.len(), 2);
+        let r = &config.robots[0];
+        assert_eq!(r.name, "Space invader");
+        assert_eq!(r.program, "space_invader.hasm");
+        assert_eq!((r.x, r.y, r.direction, r.team), (0, 7, (0, -1), 2));
+        assert_eq!(r.aoi, vec![(-1, 0), (1, 0)]);
+        assert_eq!(
+            r.components,
+            Some(vec![String::from("motor"), String::from("turret")])
+        );
+        assert_eq!(
+            (
+                r.weapon.reload_ticks,
+                r.weapon.damage,
+                r.weapon.range,
+                r.weapon.magazine_size
+            ),
+            (2, 15, 5, 3)
+        );
+        assert_eq!((r.armor.front, r.armor.side, r.armor.rear), (4, 2, 0));
+        assert_eq!(r.bullets, Some(50));
+        assert_eq!(r.registers, vec![(Register::A, 5), (Register::B, -3)]);
+
+        // Robots without keys get the defaults
+        let idle = &config.robots[1];
+        assert_eq!(idle.program, "");
+        assert_eq!(idle.components, None);
+        assert_eq!((idle.health, idle.energy, idle.bullets), (None, None, None));
+    }
+
+    #[test]
+    fn spawns_robots_as_configured() {
+        let path = write_programs("spawn", &[("bot.hasm", "fwd\nsht")]);
+        let source = "seed 7
+robot Bot
+  program bot.hasm
+  position 3 4
+  direction 1 0
+  team 2
+  components motor turret
+  weapon magazine 2
+  bullets 20
+  registers c 9
+";
+        let mut config = MatchConfig::parse(source).unwrap();
+        config.resolve_programs(&path);
+        let battle = config.start().unwrap();
+        let r = &battle.robots[0];
+        assert_eq!(r.name, "Bot");
+        assert_eq!((r.x, r.y, r.direction, r.team), (3, 4, (1, 0), 2));
+        let names: Vec<&str> = r.components.iter().map(|c| c.name()).collect();
+        assert_eq!(names, vec!["motor", "turret"]);
+        assert_eq!((r.magazine, r.bullets, r.core.c), (2, 20, 9));
+        assert_eq!(r.core.code.len(), 2);
+    }
+
+    #[test]
+    fn health_and_energy_set_the_maximum() {
+        let path = write_programs("maximum", &[("bot.hasm", "nop")]);
+        let source = "robot Bot\n  program bot.hasm\n  health 250\n  energy 300\n";
+        let mut config = MatchConfig::parse(source).unwrap();
+        config.resolve_programs(&path);
+        let mut battle = config.start().unwrap();
+        battle.pickups.push(Pickup {
+            x: 0,
+            y: 0,
+            kind: PickupKind::Health,
+        });
+        let field = Field {
+            x: 0,
+            y: 0,
+            width: 10,
+            height: 10,
+        };
+        step_game(&mut battle, &field);
+        let r = &battle.robots[0];
+        assert_eq!((r.max_health, r.health), (250, 250));
+        assert_eq!((r.max_energy, r.energy), (300, 300));
+        assert!(battle.pickups.is_empty());
+    }
+
+    #[test]
+    fn rejects_programs_using_missing_components() {
+        let path = write_programs("loadout", &[("bot.hasm", "fwd\nrad\nsht")]);
+        let mut config =
+            MatchConfig::parse("robot Bot\n  program bot.hasm\n  components motor turret\n")
+                .unwrap();
+        config.resolve_programs(&path);
+        assert_eq!(
+            config.start().err(),
+            Some(String::from(
+                "Line 2: Bot has no component providing instruction rad"
+            ))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_configurations() {
+        let errors = [
+            (
+                "robot R\n  components motor laser",
+                "Line 2: unknown component laser",
+            ),
+            ("field 0 10", "Line 1: invalid field size"),
+            ("position 1 1", "Line 1: position outside of a robot"),
+            ("robot R\n  direction 1 1", "Line 2: invalid direction"),
+            (
+                "robot R\n  position 1",
+                "Line 2: expected 2 arguments, found 1",
+            ),
+            ("robot R\n  team one", "Line 2: one is not a number"),
+            (
+                "robot R\n  aoi 1 0 1",
+                "Line 2: aoi needs pairs of coordinates",
+            ),
+            ("robot R\n  weapon speed 3", "Line 2: unknown stat speed"),
+            (
+                "robot R\n  registers ip 3",
+                "Line 2: ip is not a general purpose register",
+            ),
+            ("pickup gold 1 1", "Line 1: unknown pickup gold"),
+            ("seed -1", "Line 1: -1 is not a valid seed"),
+            ("seed 1 2", "Line 1: expected 1 argument, found 2"),
+            ("robot R\n  wings 2", "Line 2: unknown key wings"),
+        ];
+        for (source, error) in errors {
+            assert_eq!(
+                MatchConfig::parse(source).err(),
+                Some(String::from(error)),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn seeds_use_the_whole_range() {
+        let config = MatchConfig::parse("seed 18446744073709551615\n").unwrap();
+        assert_eq!(config.seed, u64::MAX);
+    }
+
+    #[test]
+    fn robots_need_a_program() {
+        let config = MatchConfig::parse("robot Lazy\n").unwrap();
+        assert_eq!(
+            config.start().err(),
+            Some(String::from("Robot Lazy has no program"))
+        );
+    }
+}
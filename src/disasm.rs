@@ -0,0 +1,110 @@
+use crate::bytecode::*;
+
+// Address the instruction jumps to, if it's known before running the program
+fn jump_target(op: &Op, len: usize) -> Option<usize> {
+    match op.dest {
+        Operand::Immediate(ip) if op.opcode.is_jump() && ip >= 0 && (ip as usize) < len => {
+            Some(ip as usize)
+        }
+        _ => None,
+    }
+}
+
+fn label(ip: usize) -> String {
+    format!("l{}", ip)
+}
+
+fn operand(operand: Operand) -> String {
+    match operand {
+        Operand::None => String::new(),
+        Operand::Register(reg) => reg.name().to_string(),
+        // The assembler only reads non-negative numbers, which wrap around
+        Operand::Immediate(imm) => (imm as u16).to_string(),
+        Operand::Constant(constant) => constant.name().to_string(),
+    }
+}
+
+// Renders a compiled program as source. Names of labels are lost when compiling,
+// so every jump target gets a label named after its address. Returns the source
+// and the line of each instruction in it.
+pub fn disassemble(code: &[Op]) -> (String, Vec<usize>) {
+    let mut targets: Vec<usize> = code
+        .iter()
+        .filter_map(|op| jump_target(op, code.len()))
+        .collect();
+    targets.sort();
+    targets.dedup();
+
+    let mut source = String::new();
+    let mut lines = vec![];
+    let mut line = 0;
+    for (ip, op) in code.iter().enumerate() {
+        if targets.binary_search(&ip).is_ok() {
+            source.push_str(&format!("{}:\n", label(ip)));
+            line += 1;
+        }
+
+        source.push_str("  ");
+        source.push_str(op.opcode.name());
+        if let Some(target) = jump_target(op, code.len()) {
+            source.push_str(&format!(" {}", label(target)));
+        } else if op.dest != Operand::None {
+            source.push_str(&format!(" {}", operand(op.dest)));
+            if op.src != Operand::None {
+                source.push_str(&format!(", {}", operand(op.src)));
+            }
+        }
+        source.push('\n');
+        lines.push(line);
+        line += 1;
+    }
+    (source, lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::*;
+    use std::path::Path;
+
+    fn compile(source: &str) -> Vec<Op> {
+        let (instructions, labels, _) = parse_file(Path::new(""), source).unwrap();
+        assemble(&instructions, &labels).unwrap().code
+    }
+
+    #[test]
+    fn disassembles_instructions() {
+        let code =
+            compile("main:\n  mov a, 3\nloop:\n  fwd\n  sub a, 1\n  jg loop\n  rad\n  jmp main");
+        let (source, lines) = disassemble(&code);
+        assert_eq!(
+            source,
+            "l0:\n  mov a, 3\nl1:\n  fwd\n  sub a, 1\n  jg l1\n  rad\n  jmp l0\n"
+        );
+        assert_eq!(lines, vec![1, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn keeps_jumps_out_of_the_program() {
+        let code = compile("jmp 7\njmp a\nnop");
+        assert_eq!(disassemble(&code).0, "  jmp 7\n  jmp a\n  nop\n");
+    }
+
+    #[test]
+    fn writes_negative_numbers_as_the_assembler_reads_them() {
+        let code = compile("mov a, 65531\nadd a, 32768\nsub b, 32767");
+        assert_eq!(code[0].src, Operand::Immediate(-5));
+        assert_eq!(code[1].src, Operand::Immediate(i16::MIN));
+        let (source, _) = disassemble(&code);
+        assert_eq!(source, "  mov a, 65531\n  add a, 32768\n  sub b, 32767\n");
+        assert_eq!(compile(&source), code);
+    }
+
+    #[test]
+    fn disassembly_compiles_to_the_same_code() {
+        let code = compile(
+            "start: rad\nje fire\nrol\njmp start\nfire: sht\nrld\nmov c, start\nsnd cx\nrcv b\nfnd 2",
+        );
+        assert_eq!(compile(&disassemble(&code).0), code);
+    }
+}
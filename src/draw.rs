@@ -1,5 +1,10 @@
 use super::*;
 
+use crate::pickup::*;
+use crate::radio::*;
+
+use std::collections::VecDeque;
+
 // Draws tile of isometric grid
 pub fn draw_tile(d: &mut RaylibDrawHandle, x: i32, y: i32, color: Color) {
     let pts = [
@@ -62,6 +67,34 @@ pub fn draw_plane(d: &mut RaylibDrawHandle, field: &Field) {
     }
 }
 
+pub fn draw_pickup(d: &mut RaylibDrawHandle, pickup: &Pickup, field: &Field, sprites: &Texture2D) {
+    let pos = coord_to_pos(pickup.x, pickup.y, field);
+    let sprite_x = match pickup.kind {
+        PickupKind::Ammo => 700.,
+        PickupKind::Health => 800.,
+        PickupKind::Energy => 900.,
+    };
+
+    d.draw_texture_pro(
+        sprites,
+        Rectangle {
+            x: sprite_x,
+            y: 0.,
+            width: 100.,
+            height: 100.,
+        },
+        Rectangle {
+            x: pos.x,
+            y: pos.y - T_WIDTH as f32 + T_HEIGHT as f32 / 2.,
+            width: T_WIDTH as f32,
+            height: T_WIDTH as f32,
+        },
+        Vector2::zero(),
+        0.,
+        Color::WHITE,
+    );
+}
+
 pub fn draw_robot(
     d: &mut RaylibDrawHandle,
     robot: &Robot,
@@ -83,6 +116,15 @@ pub fn draw_robot(
         draw_tile(d, t_pos.x as i32, t_pos.y as i32, Color::RED);
     }
 
+    if robot.shield > 0 {
+        draw_tile(
+            d,
+            robot_pos.x as i32,
+            robot_pos.y as i32,
+            Color::new(102, 191, 255, 200),
+        );
+    }
+
     // Draw sprite
     let texture_rect = match robot.direction {
         (0, -1) => Rectangle {
@@ -154,6 +196,13 @@ pub fn draw_robot(
         10,
         Color::BLUE,
     );
+    d.draw_rectangle(
+        health_pos.x as i32,
+        health_pos.y as i32 + 40,
+        (T_WIDTH as f32 * (robot.energy as f32 / robot.max_energy as f32)) as i32,
+        10,
+        Color::YELLOW,
+    );
 
     d.draw_text(
         robot.name.as_str(),
@@ -164,7 +213,12 @@ pub fn draw_robot(
     );
 }
 
-pub fn draw_robot_info(d: &mut RaylibDrawHandle, robot: &Robot, sprites: &Texture2D) {
+pub fn draw_robot_info(
+    d: &mut RaylibDrawHandle,
+    robot: &Robot,
+    radio: &TeamRadio,
+    sprites: &Texture2D,
+) {
     // Draw panel
     let info_width = 500;
     let info_height = 800;
@@ -223,4 +277,74 @@ pub fn draw_robot_info(d: &mut RaylibDrawHandle, robot: &Robot, sprites: &Textur
         30,
         Color::BLACK,
     );
+    d.draw_text(
+        format!("Energy: {:>24}/{}", robot.energy, robot.max_energy).as_str(),
+        info_rect.x as i32 + 10,
+        info_rect.y as i32 + 500,
+        30,
+        Color::BLACK,
+    );
+
+    d.draw_text(
+        format!(
+            "Magazine: {:>22}/{}",
+            robot.loaded(),
+            robot.weapon.magazine_size
+        )
+        .as_str(),
+        info_rect.x as i32 + 10,
+        info_rect.y as i32 + 530,
+        30,
+        Color::BLACK,
+    );
+
+    let components: Vec<&str> = robot.components.iter().map(|c| c.name()).collect();
+    d.draw_text(
+        format!("Components: {}", components.join(", ")).as_str(),
+        info_rect.x as i32 + 10,
+        info_rect.y as i32 + 560,
+        30,
+        Color::BLACK,
+    );
+
+    // Draw messages waiting to be received
+    let pending = radio.pending(robot.team, robot.id, robot.radio_cursor);
+    d.draw_text(
+        format!("Radio: {:>25}", pending.len()).as_str(),
+        info_rect.x as i32 + 10,
+        info_rect.y as i32 + 600,
+        30,
+        Color::BLACK,
+    );
+    for (i, m) in pending.iter().take(5).enumerate() {
+        d.draw_text(
+            format!("  {} -> {}", m.sender_name, m.value).as_str(),
+            info_rect.x as i32 + 10,
+            info_rect.y as i32 + 630 + 30 * i as i32,
+            30,
+            Color::DARKGRAY,
+        );
+    }
+}
+
+// Draws the last hits of the battle in the bottom left corner
+pub fn draw_damage_log(d: &mut RaylibDrawHandle, events: &VecDeque<DamageEvent>) {
+    let max_lines = 5;
+    let y0 = d.get_screen_height() - 40 * max_lines as i32 - 10;
+
+    let first = events.len().saturating_sub(max_lines);
+    for (i, e) in events.iter().skip(first).enumerate() {
+        let text = if e.shielded {
+            format!(
+                "{:>5} {} -> {}: blocked by shield",
+                e.tick, e.attacker, e.target
+            )
+        } else {
+            format!(
+                "{:>5} {} -> {}: {} damage ({:?})",
+                e.tick, e.attacker, e.target, e.dealt, e.side
+            )
+        };
+        d.draw_text(text.as_str(), 10, y0 + 40 * i as i32, 30, Color::BLACK);
+    }
 }
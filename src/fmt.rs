@@ -0,0 +1,234 @@
+use crate::parser::lexer::*;
+
+// Indentation of instructions under labels. Labels start at the beginning of the line.
+const INDENT: &str = "  ";
+
+enum Line {
+    Blank,
+    Label(String),
+    Directive(String),
+    Instruction(String),
+    Comment(String),
+}
+
+// Text of a token as written in the source. Identifiers keep their case, since the
+// lexer lowercases them.
+fn text(source: &str, token: &Token) -> String {
+    match token.t {
+        TokenType::Identifier => source[token.span.offset..]
+            .chars()
+            .take_while(|c| c.is_alphanumeric() || *c == '.')
+            .collect(),
+        TokenType::Text => format!("\"{}\"", token.value),
+        _ => token.value.clone(),
+    }
+}
+
+// Splits the tokens of a line of source into labels, instructions and comments
+fn split_line(source: &str, tokens: &[&Token], out: &mut Vec<Line>) {
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = tokens[i];
+        let next = tokens.get(i + 1);
+        if token.t == TokenType::Comment {
+            // Comments after code stay on the same line
+            match out.last_mut() {
+                Some(Line::Label(text))
+                | Some(Line::Directive(text))
+                | Some(Line::Instruction(text))
+                    if i > 0 =>
+                {
+                    text.push(' ');
+                    text.push_str(&token.value);
+                }
+                _ => out.push(Line::Comment(token.value.clone())),
+            }
+            i += 1;
+        } else if token.t == TokenType::Identifier
+            && next.is_some_and(|n| n.t == TokenType::Punctuator && n.value == ":")
+        {
+            out.push(Line::Label(format!("{}:", text(source, token))));
+            i += 2;
+        } else {
+            // An opcode or directive and everything up to the next opcode, label or
+            // comment. Macros can take opcodes as arguments, so their uses only end at
+            // labels and comments.
+            let call = token.t == TokenType::Identifier;
+            let mut line = text(source, token);
+            i += 1;
+            while i < tokens.len() {
+                let t = tokens[i];
+                let label = t.t == TokenType::Identifier
+                    && tokens
+                        .get(i + 1)
+                        .is_some_and(|n| n.t == TokenType::Punctuator && n.value == ":");
+                if (t.t == TokenType::Opcode && !call) || t.t == TokenType::Comment || label {
+                    break;
+                }
+                if t.value != "," {
+                    line.push(' ');
+                }
+                line.push_str(&text(source, t));
+                i += 1;
+            }
+            if token.t == TokenType::Directive {
+                out.push(Line::Directive(line));
+            } else {
+                out.push(Line::Instruction(line));
+            }
+        }
+    }
+}
+
+// Formats the source of a program: labels and directives at the beginning of the
+// line, instructions indented under labels, lowercase opcodes and registers, one space
+// between operands and at most one blank line in a row. Comments are kept.
+pub fn format_source(source: &str) -> Result<String, String> {
+    let tokens = lex(source)?;
+
+    let mut lines = vec![];
+    let mut t = 0;
+    for (n, _) in source.lines().enumerate() {
+        let start = t;
+        while t < tokens.len() && tokens[t].span.line == n {
+            t += 1;
+        }
+        if start == t {
+            lines.push(Line::Blank);
+            continue;
+        }
+        let line: Vec<&Token> = tokens[start..t].iter().collect();
+        split_line(source, &line, &mut lines);
+    }
+
+    // Instructions before the first label aren't indented, the ones in macros always
+    // are
+    let mut indent = "";
+    let mut outside = "";
+    let mut out = String::new();
+    for (i, line) in lines.iter().enumerate() {
+        match line {
+            Line::Blank => {
+                let previous_blank = i == 0 || matches!(lines[i - 1], Line::Blank);
+                if !previous_blank {
+                    out.push('\n');
+                }
+            }
+            Line::Label(text) => {
+                indent = INDENT;
+                out.push_str(text);
+                out.push('\n');
+            }
+            Line::Directive(text) => {
+                if text.starts_with(".macro") {
+                    outside = indent;
+                    indent = INDENT;
+                } else if text.starts_with(".endm") {
+                    indent = outside;
+                }
+                out.push_str(text);
+                out.push('\n');
+            }
+            Line::Instruction(text) => {
+                out.push_str(indent);
+                out.push_str(text);
+                out.push('\n');
+            }
+            Line::Comment(text) => {
+                // Comments go with the code that follows them
+                let next = lines[i + 1..]
+                    .iter()
+                    .find(|l| !matches!(l, Line::Blank | Line::Comment(_)));
+                if let Some(Line::Instruction(_)) = next {
+                    out.push_str(indent);
+                }
+                out.push_str(text);
+                out.push('\n');
+            }
+        }
+    }
+
+    // Blank lines at the end of the file
+    while out.ends_with("\n\n") {
+        out.pop();
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn f(source: &str) -> String {
+        format_source(source).unwrap()
+    }
+
+    #[test]
+    fn indents_instructions_under_labels() {
+        assert_eq!(
+            f("main:\nfwd\n    loop:\n        rol\njmp loop\n"),
+            "main:\n  fwd\nloop:\n  rol\n  jmp loop\n"
+        );
+    }
+
+    #[test]
+    fn normalizes_operands() {
+        assert_eq!(
+            f("MOV A,10\nadd  b ,  Dir\nJmp   Main\nMain:"),
+            "mov a, 10\nadd b, dir\njmp Main\nMain:\n"
+        );
+    }
+
+    #[test]
+    fn splits_lines_with_many_statements() {
+        assert_eq!(f("start: rad fwd"), "start:\n  rad\n  fwd\n");
+    }
+
+    #[test]
+    fn keeps_comments() {
+        assert_eq!(
+            f("; Turret\nmain:   ; start\n   ; aim\n rad ;look\n\n; end\n"),
+            "; Turret\nmain: ; start\n  ; aim\n  rad ;look\n\n; end\n"
+        );
+    }
+
+    #[test]
+    fn keeps_directives_at_the_beginning_of_the_line() {
+        assert_eq!(
+            f("  .EQU   SPEED  3\nmain:\n   .equ Ten 10 ; ten\nmov a,Ten"),
+            ".equ SPEED 3\nmain:\n.equ Ten 10 ; ten\n  mov a, Ten\n"
+        );
+    }
+
+    #[test]
+    fn indents_macros() {
+        assert_eq!(
+            f(".MACRO Fire turn,back\nturn\nsht\nrld\nback\n.endm\nFire  rol,ror\nmain:\nfire ror , rol"),
+            ".macro Fire turn, back\n  turn\n  sht\n  rld\n  back\n.endm\nFire rol, ror\nmain:\n  fire ror, rol\n"
+        );
+    }
+
+    #[test]
+    fn keeps_includes() {
+        assert_eq!(
+            f("Main:\njmp Aim.Face\n  .include   \"lib/Aim.hasm\""),
+            "Main:\n  jmp Aim.Face\n.include \"lib/Aim.hasm\"\n"
+        );
+    }
+
+    #[test]
+    fn collapses_blank_lines() {
+        assert_eq!(f("\n\nfwd\n\n\n\nrol\n\n\n"), "fwd\n\nrol\n");
+    }
+
+    #[test]
+    fn formatting_is_stable() {
+        let formatted = f(include_str!("../space_invader.hasm"));
+        assert_eq!(f(&formatted), formatted);
+    }
+
+    #[test]
+    fn rejects_lexical_errors() {
+        assert!(format_source("mov a, -1").is_err());
+    }
+}
@@ -0,0 +1,126 @@
+// Property tests feeding arbitrary sources to the parser and arbitrary programs to the
+// engine. Programs submitted by players can't be trusted, so none of this may panic.
+use super::*;
+
+use crate::bytecode::{decode, MAGIC, VERSION};
+use crate::parser::parse_file;
+use proptest::prelude::*;
+use std::path::Path;
+
+const FIELD: Field = Field {
+    x: 0,
+    y: 0,
+    width: 10,
+    height: 10,
+};
+
+// Pieces of programs, so that sources are close enough to real programs to get past
+// the parser and reach the engine
+const WORDS: &[&str] = &[
+    "nop", "mov", "fwd", "rol", "ror", "sht", "rld", "see", "jmp", "je", "jg", "jl", "add", "sub",
+    "cmp", "and", "or", "xor", "not", "rnd", "rad", "chk", "fnd", "shd", "snd", "rcv", "a", "b",
+    "c", "ip", "dir", "cx", "cy", "msg", "energy", "loaded", "shield", "0", "1", "2", "3", "255",
+    "32767", "32768", "99999", "loop", "end", ",", ":", "-", ";", "#", "\n", "\t", "é", "MOV",
+];
+
+fn program() -> impl Strategy<Value = String> {
+    prop::collection::vec(
+        (
+            prop::sample::select(WORDS),
+            prop::sample::select(&[" ", "\n", ", "][..]),
+        ),
+        0..60,
+    )
+    .prop_map(|words| {
+        words
+            .into_iter()
+            .map(|(word, space)| format!("{}{}", word, space))
+            .collect()
+    })
+}
+
+fn direction() -> impl Strategy<Value = (i32, i32)> {
+    prop::sample::select(&[(0, 1), (1, 0), (0, -1), (-1, 0)][..])
+}
+
+// Program, position, direction and team of a robot
+type RobotSpec = (String, i32, i32, (i32, i32), i32);
+
+fn robot_spec() -> impl Strategy<Value = RobotSpec> {
+    (program(), 0..10, 0..10, direction(), 1..3)
+}
+
+fn build_robot(spec: RobotSpec) -> Robot {
+    let (source, x, y, direction, team) = spec;
+    let mut robot = Robot {
+        x,
+        y,
+        direction,
+        team,
+        aoi: vec![(0, 1), (1, 0), (-1, 0), (0, -1)],
+        core: Core {
+            source,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    // Programs that don't compile are left empty, like in the window
+    let _ = robot.compile();
+    robot
+}
+
+proptest! {
+    #[test]
+    fn parse_never_panics(source in ".*") {
+        let _ = parse_file(Path::new(""), &source);
+    }
+
+    #[test]
+    fn parse_never_panics_on_program_like_sources(source in program()) {
+        let _ = parse_file(Path::new(""), &source);
+    }
+
+    #[test]
+    fn decode_never_panics(bytes in prop::collection::vec(any::<u8>(), 0..100)) {
+        let _ = decode(&bytes);
+    }
+
+    #[test]
+    fn decode_never_panics_on_valid_headers(ops in prop::collection::vec(any::<[u8; 8]>(), 0..8)) {
+        // Every instruction takes 6 bytes of code and 2 of line table
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(ops.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&ops.concat());
+        let _ = decode(&bytes);
+    }
+
+    #[test]
+    fn battles_never_panic(specs in prop::collection::vec(robot_spec(), 1..5), seed in any::<u64>()) {
+        let robots = specs.into_iter().map(build_robot).collect();
+        let mut battle = Battle::new(robots, seed);
+        battle.pickup_rate = 7;
+        for _ in 0..200 {
+            step_game(&mut battle, &FIELD);
+        }
+    }
+
+    #[test]
+    fn registers_never_overflow(a in any::<i16>(), b in any::<i16>(), ip in any::<i16>()) {
+        let mut robot = Robot {
+            core: Core {
+                source: String::from("add a, b\nsub b, a\ncmp a, b\nmov ip, c\njmp c\nje b"),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        robot.compile().unwrap();
+        robot.core.a = a;
+        robot.core.b = b;
+        robot.core.c = ip;
+        let mut battle = Battle::new(vec![robot], 0);
+        for _ in 0..20 {
+            step_game(&mut battle, &FIELD);
+        }
+    }
+}
@@ -0,0 +1,157 @@
+// Minimal JSON document, enough to describe the results of matches
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    UInt(u64), // For values that can go past i64::MAX, like seeds
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>), // Keys keep the order they are added in
+}
+
+impl Json {
+    pub fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(k, v)| (String::from(k), v))
+                .collect(),
+        )
+    }
+
+    pub fn str(s: &str) -> Json {
+        Json::Str(String::from(s))
+    }
+
+    // Serializes the document with two spaces of indentation
+    pub fn to_pretty_string(&self) -> String {
+        let mut out = String::new();
+        self.write(&mut out, 0);
+        out.push('\n');
+        out
+    }
+
+    fn write(&self, out: &mut String, indent: usize) {
+        match self {
+            Json::Null => out.push_str("null"),
+            Json::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            Json::Int(n) => out.push_str(&n.to_string()),
+            Json::UInt(n) => out.push_str(&n.to_string()),
+            Json::Str(s) => write_string(out, s),
+            Json::Array(items) => {
+                // Arrays of plain values, like coordinates, stay on one line
+                let nested = items
+                    .iter()
+                    .any(|i| matches!(i, Json::Array(_) | Json::Object(_)));
+                if !nested {
+                    out.push('[');
+                    for (i, item) in items.iter().enumerate() {
+                        if i > 0 {
+                            out.push_str(", ");
+                        }
+                        item.write(out, indent);
+                    }
+                    out.push(']');
+                    return;
+                }
+                out.push_str("[\n");
+                for (i, item) in items.iter().enumerate() {
+                    out.push_str(&"  ".repeat(indent + 1));
+                    item.write(out, indent + 1);
+                    out.push_str(if i + 1 < items.len() { ",\n" } else { "\n" });
+                }
+                out.push_str(&"  ".repeat(indent));
+                out.push(']');
+            }
+            Json::Object(fields) => {
+                if fields.is_empty() {
+                    out.push_str("{}");
+                    return;
+                }
+                out.push_str("{\n");
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    out.push_str(&"  ".repeat(indent + 1));
+                    write_string(out, key);
+                    out.push_str(": ");
+                    value.write(out, indent + 1);
+                    out.push_str(if i + 1 < fields.len() { ",\n" } else { "\n" });
+                }
+                out.push_str(&"  ".repeat(indent));
+                out.push('}');
+            }
+        }
+    }
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_numbers_of_any_size() {
+        let doc = Json::Array(vec![Json::Int(i64::MIN), Json::UInt(u64::MAX)]);
+        assert_eq!(
+            doc.to_pretty_string(),
+            "[-9223372036854775808, 18446744073709551615]\n"
+        );
+        let doc = Json::Array(vec![Json::Int(-42), Json::Int(0), Json::UInt(0)]);
+        assert_eq!(doc.to_pretty_string(), "[-42, 0, 0]\n");
+    }
+
+    #[test]
+    fn escapes_strings() {
+        let doc = Json::str("say \"hi\" \\ \n\r\t\u{1}\u{1f}");
+        assert_eq!(
+            doc.to_pretty_string(),
+            "\"say \\\"hi\\\" \\\\ \\n\\r\\t\\u0001\\u001f\"\n"
+        );
+        // Non-ASCII characters are valid in JSON strings and are written as they are
+        assert_eq!(Json::str("héllo ✓").to_pretty_string(), "\"héllo ✓\"\n");
+        // Keys are escaped like values
+        let doc = Json::object(vec![("a\"b", Json::Null)]);
+        assert_eq!(doc.to_pretty_string(), "{\n  \"a\\\"b\": null\n}\n");
+    }
+
+    #[test]
+    fn indents_nested_documents() {
+        let doc = Json::object(vec![
+            ("flag", Json::Bool(true)),
+            ("position", Json::Array(vec![Json::Int(1), Json::Int(2)])),
+            ("empty", Json::object(vec![])),
+            (
+                "items",
+                Json::Array(vec![Json::object(vec![("name", Json::str("x"))])]),
+            ),
+        ]);
+        assert_eq!(
+            doc.to_pretty_string(),
+            "{
+  \"flag\": true,
+  \"position\": [1, 2],
+  \"empty\": {},
+  \"items\": [
+    {
+      \"name\": \"x\"
+    }
+  ]
+}
+"
+        );
+    }
+}
@@ -0,0 +1,440 @@
+use super::*;
+
+use crate::bytecode::*;
+use crate::rng::hash;
+
+pub const INITIAL_RATING: f64 = 1500.;
+const K_FACTOR: f64 = 32.;
+
+// Number of rating changes kept in the history, two for every match. When the history
+// is full, the changes of the oldest match are dropped.
+pub const HISTORY_SIZE: usize = 2000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Win,
+    Loss,
+    Draw,
+}
+
+impl Outcome {
+    pub fn from_name(name: &str) -> Option<Outcome> {
+        match name {
+            "win" => Some(Outcome::Win),
+            "loss" => Some(Outcome::Loss),
+            "draw" => Some(Outcome::Draw),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Outcome::Win => "win",
+            Outcome::Loss => "loss",
+            Outcome::Draw => "draw",
+        }
+    }
+
+    fn score(&self) -> f64 {
+        match self {
+            Outcome::Win => 1.,
+            Outcome::Loss => 0.,
+            Outcome::Draw => 0.5,
+        }
+    }
+
+    fn opposite(&self) -> Outcome {
+        match self {
+            Outcome::Win => Outcome::Loss,
+            Outcome::Loss => Outcome::Win,
+            Outcome::Draw => Outcome::Draw,
+        }
+    }
+}
+
+// Rating of a revision of a program. Every revision gets its own rating, so they can
+// be compared with each other.
+pub struct Rating {
+    pub hash: u64, // Hash of the program's source
+    pub name: String,
+    pub rating: f64,
+    pub wins: u32,
+    pub losses: u32,
+    pub draws: u32,
+}
+
+pub struct RatingChange {
+    pub game: u32, // Number of the match on the ladder
+    pub hash: u64,
+    pub name: String,
+    pub opponent: u64, // Hash of the opponent's program
+    pub outcome: Outcome,
+    pub old: f64,
+    pub new: f64,
+}
+
+// Elo ratings of robot programs, stored in a file in the following format:
+//
+//   # HASH RATING WINS LOSSES DRAWS NAME
+//   rating 3f6a0c2b9e1d4a77 1516.00 1 0 0 space_invader
+//   # GAME HASH OPPONENT OUTCOME OLD NEW NAME
+//   change 1 3f6a0c2b9e1d4a77 8d0e3b1f5c2a6e90 win 1500.00 1516.00 space_invader
+//
+// A ladder loaded from a file is saved to it after every match. Only the most recent
+// changes are kept.
+#[derive(Default)]
+pub struct Ladder {
+    pub ratings: Vec<Rating>,
+    pub history: Vec<RatingChange>,
+    pub games: u32,
+    pub path: Option<String>,
+}
+
+// Hash of the compiled program rather than of its source, so editing a library it
+// includes starts a new revision too. A .hbc file hashes the same as its source.
+pub fn program_hash(program: &ProgramFile) -> Result<u64, String> {
+    match program {
+        ProgramFile::Source(path, source) => {
+            let mut core = Core {
+                source: source.clone(),
+                path: path.clone(),
+                ..Default::default()
+            };
+            core.compile()?;
+            Ok(hash(&encode(&Bytecode {
+                code: core.code,
+                lines: core.lines,
+            })))
+        }
+        ProgramFile::Bytecode(bytes) => Ok(hash(bytes)),
+    }
+}
+
+// First digits of a hash, enough to tell revisions apart when printing
+fn short_hash(hash: u64) -> String {
+    format!("{:016x}", hash)[..8].to_string()
+}
+
+fn parse_hash(word: &str, line: usize) -> Result<u64, String> {
+    match u64::from_str_radix(word, 16) {
+        Ok(hash) => Ok(hash),
+        Err(_) => Err(format!("Line {}: {} is not a hash", line, word)),
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(word: &str, line: usize) -> Result<T, String> {
+    match word.parse::<T>() {
+        Ok(n) => Ok(n),
+        Err(_) => Err(format!("Line {}: {} is not a number", line, word)),
+    }
+}
+
+impl Ladder {
+    pub fn new() -> Ladder {
+        Ladder::default()
+    }
+
+    // Loads the ladder from a file. A missing file is an empty ladder.
+    pub fn load(path: &str) -> Result<Ladder, String> {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("Couldn't read {}: {}", path, e)),
+        };
+        match Ladder::parse(&source) {
+            Ok(mut ladder) => {
+                ladder.path = Some(path.to_string());
+                Ok(ladder)
+            }
+            Err(e) => Err(format!("{}: {}", path, e)),
+        }
+    }
+
+    pub fn parse(source: &str) -> Result<Ladder, String> {
+        let mut ladder = Ladder::new();
+        for (i, text) in source.lines().enumerate() {
+            let line = i + 1;
+            let text = text.split('#').next().unwrap_or("");
+            let words: Vec<&str> = text.split_whitespace().collect();
+            if words.is_empty() {
+                continue;
+            }
+
+            match words[0] {
+                "rating" if words.len() >= 7 => ladder.ratings.push(Rating {
+                    hash: parse_hash(words[1], line)?,
+                    rating: parse_number(words[2], line)?,
+                    wins: parse_number(words[3], line)?,
+                    losses: parse_number(words[4], line)?,
+                    draws: parse_number(words[5], line)?,
+                    name: words[6..].join(" "),
+                }),
+                "change" if words.len() >= 8 => {
+                    let outcome = match Outcome::from_name(words[4]) {
+                        Some(outcome) => outcome,
+                        None => return Err(format!("Line {}: unknown outcome {}", line, words[4])),
+                    };
+                    let change = RatingChange {
+                        game: parse_number(words[1], line)?,
+                        hash: parse_hash(words[2], line)?,
+                        name: words[7..].join(" "),
+                        opponent: parse_hash(words[3], line)?,
+                        outcome,
+                        old: parse_number(words[5], line)?,
+                        new: parse_number(words[6], line)?,
+                    };
+                    ladder.games = std::cmp::max(ladder.games, change.game);
+                    ladder.history.push(change);
+                }
+                _ => return Err(format!("Line {}: invalid entry", line)),
+            }
+        }
+
+        ladder.trim_history();
+        Ok(ladder)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let mut out = String::from("# HASH RATING WINS LOSSES DRAWS NAME\n");
+        for r in self.ratings.iter() {
+            out += &format!(
+                "rating {:016x} {:.2} {} {} {} {}\n",
+                r.hash, r.rating, r.wins, r.losses, r.draws, r.name
+            );
+        }
+        out += "# GAME HASH OPPONENT OUTCOME OLD NEW NAME\n";
+        for c in self.history.iter() {
+            out += &format!(
+                "change {} {:016x} {:016x} {} {:.2} {:.2} {}\n",
+                c.game,
+                c.hash,
+                c.opponent,
+                c.outcome.name(),
+                c.old,
+                c.new,
+                c.name
+            );
+        }
+        match fs::write(path, out) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(format!("Couldn't write {}: {}", path, e)),
+        }
+    }
+
+    fn trim_history(&mut self) {
+        let excess = self.history.len().saturating_sub(HISTORY_SIZE);
+        self.history.drain(..excess);
+    }
+
+    // Index of the rating of a program, added with the initial rating if it's new
+    fn entry(&mut self, hash: u64, name: &str) -> usize {
+        if let Some(i) = self
+            .ratings
+            .iter()
+            .position(|r| r.hash == hash && r.name == name)
+        {
+            return i;
+        }
+        self.ratings.push(Rating {
+            hash,
+            name: String::from(name),
+            rating: INITIAL_RATING,
+            wins: 0,
+            losses: 0,
+            draws: 0,
+        });
+        self.ratings.len() - 1
+    }
+
+    // Name of the opponent in a rating change, kept by the change of the opponent in
+    // the same game
+    fn opponent_name(&self, i: usize) -> &str {
+        let game = self.history[i].game;
+        let paired = [i.wrapping_sub(1), i + 1]
+            .into_iter()
+            .find(|&j| self.history.get(j).is_some_and(|c| c.game == game));
+        match paired {
+            Some(j) => &self.history[j].name,
+            None => "?",
+        }
+    }
+
+    // Updates the ratings of two programs after a match between them, saving the
+    // ladder if it was loaded from a file
+    pub fn record(
+        &mut self,
+        a: (u64, &str),
+        b: (u64, &str),
+        outcome: Outcome,
+    ) -> Result<(), String> {
+        let ia = self.entry(a.0, a.1);
+        let ib = self.entry(b.0, b.1);
+        self.games += 1;
+
+        let (ra, rb) = (self.ratings[ia].rating, self.ratings[ib].rating);
+        let expected = 1. / (1. + 10f64.powf((rb - ra) / 400.));
+        let delta = K_FACTOR * (outcome.score() - expected);
+
+        for (i, opponent, outcome, delta) in [
+            (ia, b.0, outcome, delta),
+            (ib, a.0, outcome.opposite(), -delta),
+        ] {
+            let r = &mut self.ratings[i];
+            let old = r.rating;
+            r.rating += delta;
+            match outcome {
+                Outcome::Win => r.wins += 1,
+                Outcome::Loss => r.losses += 1,
+                Outcome::Draw => r.draws += 1,
+            }
+            self.history.push(RatingChange {
+                game: self.games,
+                hash: r.hash,
+                name: r.name.clone(),
+                opponent,
+                outcome,
+                old,
+                new: r.rating,
+            });
+        }
+        self.trim_history();
+
+        match &self.path {
+            Some(path) => self.save(path),
+            None => Ok(()),
+        }
+    }
+
+    pub fn print_leaderboard(&self) {
+        let mut ratings: Vec<&Rating> = self.ratings.iter().collect();
+        ratings.sort_by(|a, b| b.rating.total_cmp(&a.rating).then(a.name.cmp(&b.name)));
+
+        println!(
+            "{:<6}{:<24}{:<10}{:>8}{:>6}{:>8}{:>7}",
+            "Rank", "Program", "Hash", "Rating", "Wins", "Losses", "Draws"
+        );
+        for (i, r) in ratings.iter().enumerate() {
+            println!(
+                "{:<6}{:<24}{:<10}{:>8.0}{:>6}{:>8}{:>7}",
+                i + 1,
+                r.name,
+                short_hash(r.hash),
+                r.rating,
+                r.wins,
+                r.losses,
+                r.draws
+            );
+        }
+    }
+
+    // Prints the rating changes of the programs with the given name, or of all of
+    // them if no name is given
+    pub fn print_history(&self, name: Option<&str>) {
+        println!(
+            "{:<6}{:<24}{:<10}{:<24}{:<6}{:>8}{:>8}",
+            "Game", "Program", "Hash", "Opponent", "", "Old", "New"
+        );
+        for (i, c) in self.history.iter().enumerate() {
+            if name.is_some_and(|name| name != c.name) {
+                continue;
+            }
+            println!(
+                "{:<6}{:<24}{:<10}{:<24}{:<6}{:>8.0}{:>8.0}",
+                c.game,
+                c.name,
+                short_hash(c.hash),
+                self.opponent_name(i),
+                c.outcome.name(),
+                c.old,
+                c.new
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_keeps_the_names_of_copies() {
+        let mut ladder = Ladder::new();
+        ladder
+            .record((7, "original"), (7, "copy"), Outcome::Win)
+            .unwrap();
+        let names: Vec<&str> = ladder.history.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["original", "copy"]);
+        assert_eq!(ladder.opponent_name(0), "copy");
+        assert_eq!(ladder.opponent_name(1), "original");
+    }
+
+    #[test]
+    fn history_only_keeps_the_last_matches() {
+        let mut ladder = Ladder::new();
+        for _ in 0..HISTORY_SIZE {
+            ladder.record((1, "a"), (2, "b"), Outcome::Win).unwrap();
+        }
+        assert_eq!(ladder.history.len(), HISTORY_SIZE);
+        assert_eq!(ladder.history[0].game as usize, HISTORY_SIZE / 2 + 1);
+        assert_eq!(ladder.opponent_name(0), "b");
+        assert_eq!(ladder.ratings[0].wins as usize, HISTORY_SIZE);
+    }
+
+    #[test]
+    fn loaded_ladders_are_saved_after_every_match() {
+        let path = std::env::temp_dir().join(format!("hbot-ladder-{}", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let _ = fs::remove_file(&path);
+
+        let mut ladder = Ladder::load(&path).unwrap();
+        ladder.record((1, "a b"), (2, "c"), Outcome::Draw).unwrap();
+        let saved = Ladder::load(&path).unwrap();
+        assert_eq!(saved.games, 1);
+        assert_eq!(saved.history[0].name, "a b");
+        assert_eq!(saved.opponent_name(0), "c");
+        assert_eq!(saved.ratings.len(), 2);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn changes_need_a_name() {
+        assert_eq!(
+            Ladder::parse("change 1 0000000000000001 0000000000000002 win 1500.00 1516.00\n").err(),
+            Some(String::from("Line 1: invalid entry"))
+        );
+    }
+
+    #[test]
+    fn hashes_cover_included_libraries() {
+        let dir = std::env::temp_dir().join(format!("hbot-ladder-hash-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("bot.hasm");
+        let lib = dir.join("lib.hasm");
+        fs::write(&main, ".include \"lib.hasm\"\njmp lib.start\n").unwrap();
+        let hash_of = |lib_source: &str| {
+            fs::write(&lib, lib_source).unwrap();
+            program_hash(&read_program(&main.to_string_lossy()).unwrap()).unwrap()
+        };
+
+        let first = hash_of("start:\nfwd\n");
+        assert_eq!(hash_of("start:\nfwd\n"), first);
+        assert_ne!(hash_of("start:\nsht\n"), first);
+
+        // Compiled programs hash the same as their source
+        let mut core = Core {
+            source: fs::read_to_string(&main).unwrap(),
+            path: main.clone(),
+            ..Default::default()
+        };
+        core.compile().unwrap();
+        let bytes = encode(&Bytecode {
+            code: core.code,
+            lines: core.lines,
+        });
+        assert_eq!(
+            program_hash(&ProgramFile::Bytecode(bytes)).unwrap(),
+            hash_of("start:\nsht\n")
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,291 @@
+use crate::bytecode::*;
+use crate::parser::include::*;
+use crate::parser::lexer::*;
+use crate::parser::*;
+
+use std::collections::HashSet;
+use std::path::Path;
+
+fn sets_flags(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::Add
+            | Opcode::Sub
+            | Opcode::Cmp
+            | Opcode::And
+            | Opcode::Or
+            | Opcode::Xor
+            | Opcode::Not
+    )
+}
+
+// Register the instruction writes to, if any
+fn written(op: &Op) -> Option<Register> {
+    match op.dest {
+        Operand::Register(reg) if op.opcode.writes() => Some(reg),
+        _ => None,
+    }
+}
+
+// Registers the instruction reads
+fn read(op: &Op) -> Vec<Register> {
+    let mut operands = vec![op.src];
+    if !matches!(op.opcode, Opcode::Mov | Opcode::Rnd | Opcode::Rcv) {
+        operands.push(op.dest);
+    }
+    operands
+        .into_iter()
+        .filter_map(|o| match o {
+            Operand::Register(reg) => Some(reg),
+            _ => None,
+        })
+        .collect()
+}
+
+// Instructions that can be executed right after the one at ip. Jumps out of the
+// program land on the first instruction, like running past the last one.
+fn successors(code: &[Op], ip: usize) -> Vec<usize> {
+    let op = &code[ip];
+    let next = if ip + 1 < code.len() { ip + 1 } else { 0 };
+    let target = match op.dest {
+        Operand::Immediate(t) if t >= 0 && (t as usize) < code.len() => t as usize,
+        _ => 0,
+    };
+
+    // Jumps to registers or constants and writes to ip can go anywhere
+    let computed =
+        op.opcode.is_jump() && matches!(op.dest, Operand::Register(_) | Operand::Constant(_));
+    if computed || written(op) == Some(Register::Ip) {
+        return (0..code.len()).collect();
+    }
+    match op.opcode {
+        Opcode::Jmp => vec![target],
+        Opcode::Je | Opcode::Jg | Opcode::Jl => vec![next, target],
+        _ => vec![next],
+    }
+}
+
+// Instructions that can be reached from the given ones, which are included
+fn reachable(code: &[Op], from: Vec<usize>) -> Vec<bool> {
+    let mut seen = vec![false; code.len()];
+    let mut stack = from;
+    while let Some(ip) = stack.pop() {
+        if seen[ip] {
+            continue;
+        }
+        seen[ip] = true;
+        stack.extend(successors(code, ip));
+    }
+    seen
+}
+
+// Instructions from which one of the given ones can be reached
+fn reaching(code: &[Op], to: Vec<usize>) -> Vec<bool> {
+    let mut predecessors = vec![vec![]; code.len()];
+    for ip in 0..code.len() {
+        for next in successors(code, ip) {
+            predecessors[next].push(ip);
+        }
+    }
+
+    let mut seen = vec![false; code.len()];
+    let mut stack = to;
+    while let Some(ip) = stack.pop() {
+        if seen[ip] {
+            continue;
+        }
+        seen[ip] = true;
+        stack.extend(predecessors[ip].iter().copied());
+    }
+    seen
+}
+
+// Looks for likely mistakes in a program, returning a warning for each of them. Only
+// the file the program is read from is checked, included files can be linted on their
+// own.
+pub fn lint(path: &Path, source: &str) -> Result<Vec<String>, String> {
+    let (instructions, labels, source_map) = parse_file(path, source)?;
+    let bytecode = assemble(&instructions, &labels).map_err(|e| source_map.locate_error(e))?;
+    let code = &bytecode.code;
+    let lines = &bytecode.lines;
+    let mut warnings: Vec<(usize, String)> = vec![];
+
+    // Labels are used if any identifier refers to them
+    let tokens = lex(source)?;
+    let mut definitions = vec![];
+    let mut used = HashSet::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if token.t != TokenType::Identifier {
+            continue;
+        }
+        let defined = tokens
+            .get(i + 1)
+            .is_some_and(|t| t.t == TokenType::Punctuator && t.value == ":");
+        if defined {
+            definitions.push(token);
+        } else {
+            used.insert(token.value.as_str());
+        }
+    }
+    for label in definitions {
+        if !used.contains(label.value.as_str()) {
+            warnings.push((
+                label.span.line,
+                format!("label {} is never used", label.value),
+            ));
+        }
+    }
+
+    if code.is_empty() {
+        return Ok(finish(warnings, &source_map));
+    }
+
+    // Unreachable code, reported once for every block of it
+    let reached = reachable(code, vec![0]);
+    for ip in 0..code.len() {
+        if !reached[ip] && (ip == 0 || reached[ip - 1]) {
+            warnings.push((lines[ip], String::from("instruction can never be reached")));
+        }
+    }
+
+    // Instructions that can run after flags have been set
+    let setters: Vec<usize> = (0..code.len())
+        .filter(|i| sets_flags(code[*i].opcode))
+        .flat_map(|i| successors(code, i))
+        .collect();
+    let flags_set = reachable(code, setters);
+
+    for ip in 0..code.len() {
+        let op = &code[ip];
+        match op.opcode {
+            Opcode::Sht => {
+                let after = reachable(code, successors(code, ip));
+                if !(0..code.len()).any(|i| after[i] && code[i].opcode == Opcode::Rld) {
+                    warnings.push((
+                        lines[ip],
+                        String::from("sht is never followed by rld, the turret won't be reloaded"),
+                    ));
+                }
+            }
+            Opcode::Je | Opcode::Jg | Opcode::Jl if !flags_set[ip] => {
+                warnings.push((
+                    lines[ip],
+                    format!(
+                        "{} checks flags no instruction before it has set",
+                        op.opcode.name()
+                    ),
+                ));
+            }
+            _ => (),
+        }
+    }
+
+    // Writes to general purpose registers the program never reads
+    for reg in [Register::A, Register::B, Register::C] {
+        let write = code.iter().position(|op| written(op) == Some(reg));
+        if let Some(ip) = write {
+            if !code.iter().any(|op| read(op).contains(&reg)) {
+                warnings.push((
+                    lines[ip],
+                    format!("register {} is written but never read", reg.name()),
+                ));
+            }
+        }
+    }
+
+    // Loops the program can get stuck in without moving, turning, shooting or reloading
+    let physical: Vec<usize> = (0..code.len())
+        .filter(|i| code[*i].opcode.is_physical())
+        .collect();
+    let can_act = reaching(code, physical);
+    let stuck = (0..code.len()).find(|i| reached[*i] && !can_act[*i]);
+    if let Some(ip) = stuck {
+        warnings.push((
+            lines[ip],
+            String::from("from here the program loops without ever performing a physical action"),
+        ));
+    }
+
+    Ok(finish(warnings, &source_map))
+}
+
+fn finish(mut warnings: Vec<(usize, String)>, source_map: &SourceMap) -> Vec<String> {
+    warnings.sort_by_key(|(line, _)| *line);
+    warnings
+        .into_iter()
+        .filter(|(line, _)| source_map.locate(*line).0 == 0)
+        .map(|(line, warning)| format!("Line {}: {}", line + 1, warning))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn l(source: &str) -> Vec<String> {
+        lint(Path::new(""), source).unwrap()
+    }
+
+    #[test]
+    fn accepts_good_programs() {
+        assert!(l(include_str!("../space_invader.hasm")).is_empty());
+        assert!(l("main:\n  rad\n  cmp dir, 0\n  je fire\n  rol\n  jmp main\nfire:\n  sht\n  rld\n  jmp main").is_empty());
+    }
+
+    #[test]
+    fn finds_unreachable_instructions() {
+        assert_eq!(
+            l("main:\n  fwd\n  jmp main\n  rol\n  ror\nend:\n  jmp main"),
+            vec![
+                "Line 4: instruction can never be reached",
+                "Line 6: label end is never used"
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_unused_labels() {
+        assert_eq!(
+            l("start:\nfwd\nunused:\nrol"),
+            vec![
+                "Line 1: label start is never used",
+                "Line 3: label unused is never used"
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_shots_without_reload() {
+        assert_eq!(
+            l("fwd\nsht"),
+            vec!["Line 2: sht is never followed by rld, the turret won't be reloaded"]
+        );
+        assert!(l("sht\nfwd\nrld").is_empty());
+    }
+
+    #[test]
+    fn finds_jumps_without_flags() {
+        assert_eq!(
+            l("main:\nje main\nfwd"),
+            vec!["Line 2: je checks flags no instruction before it has set"]
+        );
+        assert!(l("main:\ncmp a, 1\nje main\nfwd").is_empty());
+    }
+
+    #[test]
+    fn finds_registers_never_read() {
+        assert_eq!(
+            l("mov b, 3\nrnd c\nfwd\nmov c, 1\nsnd c"),
+            vec!["Line 1: register b is written but never read"]
+        );
+    }
+
+    #[test]
+    fn finds_loops_without_physical_actions() {
+        assert_eq!(
+            l("fwd\nwait:\nnop\njmp wait"),
+            vec!["Line 3: from here the program loops without ever performing a physical action"]
+        );
+        assert!(l("loop:\nnop\nmov a, loop\njmp a\nfwd").is_empty());
+    }
+}
@@ -1,6 +1,23 @@
+pub mod bytecode;
+pub mod cli;
+pub mod component;
+pub mod config;
+pub mod disasm;
 pub mod draw;
+pub mod fmt;
+#[cfg(test)]
+mod fuzz;
+pub mod json;
+pub mod ladder;
+pub mod lint;
 pub mod parser;
+pub mod pickup;
+pub mod radio;
+pub mod report;
+pub mod rng;
 pub mod robot;
+pub mod scenario;
+pub mod tournament;
 
 use raylib::prelude::*;
 use rfd::FileDialog;
@@ -8,6 +25,7 @@ use std::ffi::{CStr, CString};
 use std::fs;
 use std::os::raw::c_char;
 
+use config::*;
 use draw::*;
 use robot::*;
 
@@ -60,6 +78,16 @@ enum AnimState {
 }
 
 fn main() {
+    // Commands run headless, without opening the window
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        if let Err(e) = cli::run(&args) {
+            println!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     // Setup raylib
     let (mut rl, thread) = raylib::init()
         .size(2000, 1500)
@@ -76,35 +104,22 @@ fn main() {
 
     // Setup robots
     let player_color = Color::new(251, 97, 7, 255);
-    let enemy_color = Color::new(0, 127, 255, 255);
-
-    let mut robots: Vec<Robot> = Vec::new();
-
-    robots.push(Robot {
-        x: 0,
-        y: 9,
-        direction: (0, -1),
-        team: -1,
-        color: player_color,
-        name: String::from("Robot"),
-        aoi: vec![(-1, 0), (-1, -1), (0, -1), (1, -1), (1, 0)],
-        ..Default::default()
-    });
-    robots[0].core.source =
-        fs::read_to_string("space_invader.hasm").expect("Error loading program");
-    robots[0].core.compile();
-
-    robots.push(Robot {
-        x: 5,
-        y: 0,
-        direction: (0, 1),
-        team: 1,
-        color: enemy_color,
-        name: String::from("Robot 2"),
-        ..Default::default()
-    });
-    robots[1].core.source = fs::read_to_string("turret.hasm").expect("Error loading program");
-    robots[1].core.compile();
+
+    let config = match MatchConfig::load("match.cfg") {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Error: {}", e);
+            return;
+        }
+    };
+
+    let mut battle = match config.start() {
+        Ok(battle) => battle,
+        Err(e) => {
+            println!("Error: {}", e);
+            return;
+        }
+    };
 
     let mut game_state = GameState::Normal;
     let mut selected_robot: Option<usize> = None;
@@ -118,12 +133,16 @@ fn main() {
             rl.get_screen_width() as f32 / 2.0,
             rl.get_screen_height() as f32 / 2.0,
         );
-        let grid_pos = center + Vector2::new((-T_WIDTH / 2) as f32, (-T_HEIGHT / 2 * 10) as f32);
+        let grid_pos = center
+            + Vector2::new(
+                (-T_WIDTH / 2) as f32,
+                (-T_HEIGHT / 2 * config.height) as f32,
+            );
         let field = Field {
             x: grid_pos.x as i32,
             y: grid_pos.y as i32,
-            width: 10,
-            height: 10,
+            width: config.width,
+            height: config.height,
         };
 
         let mouse = rl.get_mouse_position();
@@ -136,11 +155,11 @@ fn main() {
                 if rl.is_key_released(KeyboardKey::KEY_ENTER) {
                     animation_state = AnimState::Moving;
                     rob_pos_snapshot.clear();
-                    for r in robots.iter() {
+                    for r in battle.robots.iter() {
                         rob_pos_snapshot.push((r.x, r.y));
                     }
                     sim_time = rl.get_time();
-                    step_game(&mut robots, &field);
+                    step_game(&mut battle, &field);
                 }
 
                 if rl.is_key_released(KeyboardKey::KEY_SPACE) {
@@ -152,11 +171,11 @@ fn main() {
                 if time - sim_time >= anim_duration {
                     animation_state = AnimState::Moving;
                     rob_pos_snapshot.clear();
-                    for r in robots.iter() {
+                    for r in battle.robots.iter() {
                         rob_pos_snapshot.push((r.x, r.y));
                     }
                     sim_time = rl.get_time();
-                    step_game(&mut robots, &field);
+                    step_game(&mut battle, &field);
                 }
 
                 if rl.is_key_released(KeyboardKey::KEY_SPACE) {
@@ -178,27 +197,29 @@ fn main() {
                 GameState::AddingRobot => {
                     let files = FileDialog::new()
                         .add_filter("text", &["txt", "hasm"])
+                        .add_filter("bytecode", &["hbc"])
                         .set_directory("/")
                         .pick_file();
 
                     if let Some(path) = files {
-                        let source = fs::read_to_string(path).expect("Error loading program");
-                        if let Some(hover_tile) = hover_tile {
-                            robots.push(Robot {
+                        let program = read_program(&path.to_string_lossy());
+                        if let Err(e) = &program {
+                            println!("Error: {}", e);
+                        }
+                        if let (Ok(program), Some(hover_tile)) = (program, hover_tile) {
+                            let mut robot = Robot {
                                 x: hover_tile.0,
                                 y: hover_tile.1,
                                 direction: (0, -1),
                                 team: -1,
                                 color: player_color,
                                 name: String::from("new"),
-                                core: Core {
-                                    source,
-                                    ..Default::default()
-                                },
                                 ..Default::default()
-                            });
-                            let last = robots.len() - 1;
-                            robots[last].core.compile();
+                            };
+                            match robot.load_program(program) {
+                                Ok(()) => battle.add(robot),
+                                Err(e) => println!("Error: {}", e),
+                            }
                         }
                     }
                     game_state = GameState::Normal;
@@ -208,7 +229,7 @@ fn main() {
                     if let Some(hover_tile) = hover_tile {
                         selected_robot = None;
                         let mut found_robot = false;
-                        for (i, robot) in robots.iter().enumerate() {
+                        for (i, robot) in battle.robots.iter().enumerate() {
                             if robot.x == hover_tile.0 && robot.y == hover_tile.1 {
                                 selected_robot = Some(i);
                                 found_robot = true;
@@ -265,7 +286,7 @@ fn main() {
             Some(CStr::from_bytes_with_nul(b"Step\0").expect("Failed to create CStr")),
         ) {
             if game_state == GameState::Normal {
-                step_game(&mut robots, &field);
+                step_game(&mut battle, &field);
             }
         }
 
@@ -286,16 +307,21 @@ fn main() {
         // robots[0].draw_core(&mut d);
         draw_plane(&mut d, &field);
 
+        // Draw crates
+        for pickup in battle.pickups.iter() {
+            draw_pickup(&mut d, pickup, &field, &sprites);
+        }
+
         // Draw robots
         match animation_state {
             AnimState::Idle => {
-                for (i, robot) in robots.iter().enumerate() {
+                for (i, robot) in battle.robots.iter().enumerate() {
                     draw_robot(&mut d, &robot, &field, &sprites, (0, 0), 1.);
                 }
             }
 
             AnimState::Moving => {
-                for (i, robot) in robots.iter().enumerate() {
+                for (i, robot) in battle.robots.iter().enumerate() {
                     draw_robot(
                         &mut d,
                         &robot,
@@ -311,7 +337,7 @@ fn main() {
         // Draw hover tile
         if let Some(hover_tile) = hover_tile {
             /*if game_state == GameState::AddingRobot {
-                draw_robot_info(&mut d, &robots[r], &sprites);
+                draw_robot_info(&mut d, &battle.robots[r], &battle.radio, &sprites);
             }*/
 
             let selection_pos = coord_to_pos(hover_tile.0, hover_tile.1, &field);
@@ -324,15 +350,16 @@ fn main() {
         }
 
         // Draw UI
+        draw_damage_log(&mut d, &battle.events);
         if let Some(r) = selected_robot {
-            let selection_pos = coord_to_pos(robots[r].x, robots[r].y, &field);
+            let selection_pos = coord_to_pos(battle.robots[r].x, battle.robots[r].y, &field);
             draw_block(
                 &mut d,
                 selection_pos.x as i32,
                 selection_pos.y as i32,
                 Color::new(255, 0, 0, 130 as u8),
             );
-            draw_robot_info(&mut d, &robots[r], &sprites);
+            draw_robot_info(&mut d, &battle.robots[r], &battle.radio, &sprites);
         }
     }
 }
@@ -1,8 +1,13 @@
+pub mod directives;
+pub mod include;
 pub mod lexer;
 
-use lexer::lex;
+use directives::*;
+use include::*;
 
-#[derive(Debug, Clone)]
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Identifier(String),
     Immediate(i16),
@@ -10,7 +15,7 @@ pub enum Value {
 }
 
 // Abstract syntax tree
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Instruction {
     Label(usize, String),
     Nop(usize),
@@ -42,37 +47,148 @@ pub enum Instruction {
     Or(usize, Value, Value),
     Xor(usize, Value, Value),
     Not(usize, Value),
+    Rnd(usize, Value),
 
     // Sensors
     Rad(usize),
     Chk(usize),
+    Fnd(usize, Value),
+
+    // Shield
+    Shd(usize),
+
+    // Radio
+    Snd(usize, Value),
+    Rcv(usize, Value),
 }
 
 use lexer::*;
 use Instruction::*;
 use Value::*;
 
-fn check_arg_c(arg_c: usize, expected: usize) -> bool {
+impl Instruction {
+    // Line of the source the instruction was parsed from
+    pub fn line(&self) -> usize {
+        match self {
+            Label(line, _)
+            | Nop(line)
+            | Fwd(line)
+            | Rol(line)
+            | Ror(line)
+            | Sht(line)
+            | Rld(line)
+            | See(line)
+            | Rad(line)
+            | Chk(line)
+            | Shd(line) => *line,
+
+            Jmp(line, _)
+            | Je(line, _)
+            | Jg(line, _)
+            | Jl(line, _)
+            | Not(line, _)
+            | Rnd(line, _)
+            | Snd(line, _)
+            | Rcv(line, _)
+            | Fnd(line, _) => *line,
+
+            Mov(line, _, _)
+            | Add(line, _, _)
+            | Sub(line, _, _)
+            | Cmp(line, _, _)
+            | And(line, _, _)
+            | Or(line, _, _)
+            | Xor(line, _, _) => *line,
+        }
+    }
+
+    pub fn opcode(&self) -> &'static str {
+        match self {
+            Label(_, _) => "label",
+            Nop(_) => "nop",
+            Mov(_, _, _) => "mov",
+            Fwd(_) => "fwd",
+            Rol(_) => "rol",
+            Ror(_) => "ror",
+            Sht(_) => "sht",
+            Rld(_) => "rld",
+            See(_) => "see",
+            Jmp(_, _) => "jmp",
+            Je(_, _) => "je",
+            Jg(_, _) => "jg",
+            Jl(_, _) => "jl",
+            Add(_, _, _) => "add",
+            Sub(_, _, _) => "sub",
+            Cmp(_, _, _) => "cmp",
+            And(_, _, _) => "and",
+            Or(_, _, _) => "or",
+            Xor(_, _, _) => "xor",
+            Not(_, _) => "not",
+            Rnd(_, _) => "rnd",
+            Rad(_) => "rad",
+            Chk(_) => "chk",
+            Fnd(_, _) => "fnd",
+            Shd(_) => "shd",
+            Snd(_, _) => "snd",
+            Rcv(_, _) => "rcv",
+        }
+    }
+
+    pub fn operands(&self) -> Vec<&Value> {
+        match self {
+            Jmp(_, val)
+            | Je(_, val)
+            | Jg(_, val)
+            | Jl(_, val)
+            | Not(_, val)
+            | Rnd(_, val)
+            | Snd(_, val)
+            | Rcv(_, val)
+            | Fnd(_, val) => vec![val],
+
+            Mov(_, dest, src)
+            | Add(_, dest, src)
+            | Sub(_, dest, src)
+            | Cmp(_, dest, src)
+            | And(_, dest, src)
+            | Or(_, dest, src)
+            | Xor(_, dest, src) => vec![dest, src],
+
+            _ => vec![],
+        }
+    }
+}
+
+fn check_arg_c(tok: &Token, arg_c: usize, expected: usize) -> Result<(), String> {
     if arg_c < expected {
-        println!("Error: Expected {} arguments, found {}", expected, arg_c);
-        return false;
+        let plural = if expected == 1 { "" } else { "s" };
+        return Err(error(
+            tok,
+            format!(
+                "{} needs {} operand{}, found {}",
+                tok.value, expected, plural, arg_c
+            ),
+        ));
     }
-    return true;
+    Ok(())
 }
 
-fn token_to_value(tok: &Token) -> Value {
+fn token_to_value(tok: &Token) -> Result<Value, String> {
     match tok.t {
-        TokenType::Register => Register(tok.value.clone()),
-        TokenType::Immediate => Immediate(tok.value.parse::<i16>().unwrap()),
-        TokenType::Identifier => Identifier(tok.value.clone()),
-        _ => {
-            println!("Couln't convert token {} to value", tok.value);
-            Identifier(String::from("ERROR"))
-        }
+        TokenType::Register => Ok(Register(tok.value.clone())),
+        TokenType::Immediate => match immediate(&tok.value) {
+            Some(imm) => Ok(Immediate(imm)),
+            None => Err(error(
+                tok,
+                format!("{} doesn't fit in a register", tok.value),
+            )),
+        },
+        TokenType::Identifier => Ok(Identifier(tok.value.clone())),
+        _ => Err(error(tok, format!("{} can't be an operand", tok.value))),
     }
 }
 
-fn p_instruction(toks: &Vec<Token>, i: &mut usize) -> Option<Instruction> {
+fn p_instruction(toks: &[Token], i: &mut usize) -> Result<Option<Instruction>, String> {
     let j = *i;
     if toks.len() > j && toks[j].t == TokenType::Opcode {
         let mut arg_c = 0;
@@ -85,7 +201,7 @@ fn p_instruction(toks: &Vec<Token>, i: &mut usize) -> Option<Instruction> {
                     == (Token {
                         value: String::from(","),
                         t: TokenType::Punctuator,
-                        line: 0,
+                        span: Span::default(),
                     })
                 && (toks[j + 3].t != TokenType::Opcode && toks[j + 3].t != TokenType::Punctuator)
             {
@@ -97,256 +213,236 @@ fn p_instruction(toks: &Vec<Token>, i: &mut usize) -> Option<Instruction> {
             }
         }
 
-        return match toks[j].value.as_str() {
+        return Ok(match toks[j].value.as_str() {
             "nop" => {
-                if check_arg_c(arg_c, 0) {
-                    *i += 1;
-                    Some(Nop(toks[j].line))
-                } else {
-                    None
-                }
+                check_arg_c(&toks[j], arg_c, 0)?;
+                *i += 1;
+                Some(Nop(toks[j].span.line))
             }
             "mov" => {
-                if check_arg_c(arg_c, 2) {
-                    *i += 4;
-                    Some(Mov(
-                        toks[j].line,
-                        token_to_value(&toks[j + 1]),
-                        token_to_value(&toks[j + 3]),
-                    ))
-                } else {
-                    None
-                }
+                check_arg_c(&toks[j], arg_c, 2)?;
+                *i += 4;
+                Some(Mov(
+                    toks[j].span.line,
+                    token_to_value(&toks[j + 1])?,
+                    token_to_value(&toks[j + 3])?,
+                ))
             }
 
             "fwd" => {
-                if check_arg_c(arg_c, 0) {
-                    *i += 1;
-                    Some(Fwd(toks[j].line))
-                } else {
-                    None
-                }
+                check_arg_c(&toks[j], arg_c, 0)?;
+                *i += 1;
+                Some(Fwd(toks[j].span.line))
             }
             "rol" => {
-                if check_arg_c(arg_c, 0) {
-                    *i += 1;
-                    Some(Rol(toks[j].line))
-                } else {
-                    None
-                }
+                check_arg_c(&toks[j], arg_c, 0)?;
+                *i += 1;
+                Some(Rol(toks[j].span.line))
             }
             "ror" => {
-                if check_arg_c(arg_c, 0) {
-                    *i += 1;
-                    Some(Ror(toks[j].line))
-                } else {
-                    None
-                }
+                check_arg_c(&toks[j], arg_c, 0)?;
+                *i += 1;
+                Some(Ror(toks[j].span.line))
             }
 
             "sht" => {
-                if check_arg_c(arg_c, 0) {
-                    *i += 1;
-                    Some(Sht(toks[j].line))
-                } else {
-                    None
-                }
+                check_arg_c(&toks[j], arg_c, 0)?;
+                *i += 1;
+                Some(Sht(toks[j].span.line))
             }
             "rld" => {
-                if check_arg_c(arg_c, 0) {
-                    *i += 1;
-                    Some(Rld(toks[j].line))
-                } else {
-                    None
-                }
+                check_arg_c(&toks[j], arg_c, 0)?;
+                *i += 1;
+                Some(Rld(toks[j].span.line))
             }
 
             "see" => {
-                if check_arg_c(arg_c, 0) {
-                    *i += 1;
-                    Some(See(toks[j].line))
-                } else {
-                    None
-                }
+                check_arg_c(&toks[j], arg_c, 0)?;
+                *i += 1;
+                Some(See(toks[j].span.line))
             }
 
             "jmp" => {
-                if check_arg_c(arg_c, 1) {
-                    *i += 2;
-                    Some(Jmp(toks[j].line, token_to_value(&toks[j + 1])))
-                } else {
-                    None
-                }
+                check_arg_c(&toks[j], arg_c, 1)?;
+                *i += 2;
+                Some(Jmp(toks[j].span.line, token_to_value(&toks[j + 1])?))
             }
             "je" => {
-                if check_arg_c(arg_c, 1) {
-                    *i += 2;
-                    Some(Je(toks[j].line, token_to_value(&toks[j + 1])))
-                } else {
-                    None
-                }
+                check_arg_c(&toks[j], arg_c, 1)?;
+                *i += 2;
+                Some(Je(toks[j].span.line, token_to_value(&toks[j + 1])?))
             }
             "jg" => {
-                if check_arg_c(arg_c, 1) {
-                    *i += 2;
-                    Some(Jg(toks[j].line, token_to_value(&toks[j + 1])))
-                } else {
-                    None
-                }
+                check_arg_c(&toks[j], arg_c, 1)?;
+                *i += 2;
+                Some(Jg(toks[j].span.line, token_to_value(&toks[j + 1])?))
             }
             "jl" => {
-                if check_arg_c(arg_c, 1) {
-                    *i += 2;
-                    Some(Jl(toks[j].line, token_to_value(&toks[j + 1])))
-                } else {
-                    None
-                }
+                check_arg_c(&toks[j], arg_c, 1)?;
+                *i += 2;
+                Some(Jl(toks[j].span.line, token_to_value(&toks[j + 1])?))
             }
 
             "add" => {
-                if check_arg_c(arg_c, 2) {
-                    *i += 4;
-                    Some(Add(
-                        toks[j].line,
-                        token_to_value(&toks[j + 1]),
-                        token_to_value(&toks[j + 3]),
-                    ))
-                } else {
-                    None
-                }
+                check_arg_c(&toks[j], arg_c, 2)?;
+                *i += 4;
+                Some(Add(
+                    toks[j].span.line,
+                    token_to_value(&toks[j + 1])?,
+                    token_to_value(&toks[j + 3])?,
+                ))
             }
             "sub" => {
-                if check_arg_c(arg_c, 2) {
-                    *i += 4;
-                    Some(Sub(
-                        toks[j].line,
-                        token_to_value(&toks[j + 1]),
-                        token_to_value(&toks[j + 3]),
-                    ))
-                } else {
-                    None
-                }
+                check_arg_c(&toks[j], arg_c, 2)?;
+                *i += 4;
+                Some(Sub(
+                    toks[j].span.line,
+                    token_to_value(&toks[j + 1])?,
+                    token_to_value(&toks[j + 3])?,
+                ))
             }
             "cmp" => {
-                if check_arg_c(arg_c, 2) {
-                    *i += 4;
-                    Some(Cmp(
-                        toks[j].line,
-                        token_to_value(&toks[j + 1]),
-                        token_to_value(&toks[j + 3]),
-                    ))
-                } else {
-                    None
-                }
+                check_arg_c(&toks[j], arg_c, 2)?;
+                *i += 4;
+                Some(Cmp(
+                    toks[j].span.line,
+                    token_to_value(&toks[j + 1])?,
+                    token_to_value(&toks[j + 3])?,
+                ))
             }
             "and" => {
-                if check_arg_c(arg_c, 2) {
-                    *i += 4;
-                    Some(And(
-                        toks[j].line,
-                        token_to_value(&toks[j + 1]),
-                        token_to_value(&toks[j + 3]),
-                    ))
-                } else {
-                    None
-                }
+                check_arg_c(&toks[j], arg_c, 2)?;
+                *i += 4;
+                Some(And(
+                    toks[j].span.line,
+                    token_to_value(&toks[j + 1])?,
+                    token_to_value(&toks[j + 3])?,
+                ))
             }
             "or" => {
-                if check_arg_c(arg_c, 2) {
-                    *i += 4;
-                    Some(Or(
-                        toks[j].line,
-                        token_to_value(&toks[j + 1]),
-                        token_to_value(&toks[j + 3]),
-                    ))
-                } else {
-                    None
-                }
+                check_arg_c(&toks[j], arg_c, 2)?;
+                *i += 4;
+                Some(Or(
+                    toks[j].span.line,
+                    token_to_value(&toks[j + 1])?,
+                    token_to_value(&toks[j + 3])?,
+                ))
             }
             "xor" => {
-                if check_arg_c(arg_c, 2) {
-                    *i += 4;
-                    Some(Xor(
-                        toks[j].line,
-                        token_to_value(&toks[j + 1]),
-                        token_to_value(&toks[j + 3]),
-                    ))
-                } else {
-                    None
-                }
+                check_arg_c(&toks[j], arg_c, 2)?;
+                *i += 4;
+                Some(Xor(
+                    toks[j].span.line,
+                    token_to_value(&toks[j + 1])?,
+                    token_to_value(&toks[j + 3])?,
+                ))
             }
             "not" => {
-                if check_arg_c(arg_c, 1) {
-                    *i += 2;
-                    Some(Not(toks[j].line, token_to_value(&toks[j + 1])))
-                } else {
-                    None
-                }
+                check_arg_c(&toks[j], arg_c, 1)?;
+                *i += 2;
+                Some(Not(toks[j].span.line, token_to_value(&toks[j + 1])?))
+            }
+            "rnd" => {
+                check_arg_c(&toks[j], arg_c, 1)?;
+                *i += 2;
+                Some(Rnd(toks[j].span.line, token_to_value(&toks[j + 1])?))
             }
             "rad" => {
-                if check_arg_c(arg_c, 0) {
-                    *i += 1;
-                    Some(Rad(toks[j].line))
-                } else {
-                    None
-                }
+                check_arg_c(&toks[j], arg_c, 0)?;
+                *i += 1;
+                Some(Rad(toks[j].span.line))
             }
             "chk" => {
-                if check_arg_c(arg_c, 0) {
-                    *i += 1;
-                    Some(Chk(toks[j].line))
-                } else {
-                    None
-                }
+                check_arg_c(&toks[j], arg_c, 0)?;
+                *i += 1;
+                Some(Chk(toks[j].span.line))
+            }
+            "fnd" => {
+                check_arg_c(&toks[j], arg_c, 1)?;
+                *i += 2;
+                Some(Fnd(toks[j].span.line, token_to_value(&toks[j + 1])?))
+            }
+            "shd" => {
+                check_arg_c(&toks[j], arg_c, 0)?;
+                *i += 1;
+                Some(Shd(toks[j].span.line))
+            }
+            "snd" => {
+                check_arg_c(&toks[j], arg_c, 1)?;
+                *i += 2;
+                Some(Snd(toks[j].span.line, token_to_value(&toks[j + 1])?))
+            }
+            "rcv" => {
+                check_arg_c(&toks[j], arg_c, 1)?;
+                *i += 2;
+                Some(Rcv(toks[j].span.line, token_to_value(&toks[j + 1])?))
             }
             _ => None,
-        };
+        });
     }
 
-    return None;
+    Ok(None)
 }
 
-fn p_label(toks: &Vec<Token>, i: &mut usize) -> Option<Instruction> {
+fn p_label(toks: &[Token], i: &mut usize) -> Option<Instruction> {
     let j = *i;
     if toks.len() > j + 1
         && toks[j].t == TokenType::Identifier
-        && toks[j + 1]
-            == (Token {
-                t: TokenType::Punctuator,
-                value: String::from(":"),
-                line: 0,
-            })
+        && toks[j + 1].t == TokenType::Punctuator
+        && toks[j + 1].value == ":"
     {
         *i += 2;
-        return Some(Label(toks[j].line, toks[j].value.clone()));
+        return Some(Label(toks[j].span.line, toks[j].value.clone()));
     }
     return None;
 }
 
-pub fn parse(source: &String) -> Vec<Instruction> {
-    let tokens = lex(&source);
+// Instructions of a program, the addresses of its labels and the files it's made of
+pub type ParsedProgram = (Vec<Instruction>, Vec<(String, i16)>, SourceMap);
+
+// Parses a program read from a file, along with the files it includes. The lines of
+// the instructions are lines of the program, the source map tells the file of each.
+pub fn parse_file(path: &Path, source: &str) -> Result<ParsedProgram, String> {
+    let (tokens, map) = include_files(path, source)?;
+    let tokens = expand_macros(tokens).map_err(|e| map.locate_error(e))?;
+    let tokens = define_constants(tokens).map_err(|e| map.locate_error(e))?;
     let mut out: Vec<Instruction> = vec![];
     let mut i = 0;
 
     let mut label_table: Vec<(String, i16)> = Vec::new();
-    let mut inst_count = 0;
+    let mut inst_count: usize = 0;
 
     // Parse instructions
     loop {
-        if let Some(inst) = p_instruction(&tokens, &mut i) {
+        if let Some(inst) = p_instruction(&tokens, &mut i).map_err(|e| map.locate_error(e))? {
             inst_count += 1;
             out.push(inst);
             continue;
         }
 
         if let Some(inst) = p_label(&tokens, &mut i) {
-            if let Label(_, id) = &inst {
-                label_table.push((id.clone(), inst_count))
+            if let Label(line, id) = &inst {
+                // Also catches included files that share their name, and so their labels
+                if label_table.iter().any(|(label, _)| label == id) {
+                    return Err(map.locate_error(format!(
+                        "Line {}: label {} is already defined",
+                        line + 1,
+                        id
+                    )));
+                }
+                label_table.push((id.clone(), inst_count as i16))
             }
             out.push(inst);
             continue;
         }
 
+        if let Some(token) = tokens.get(i) {
+            let message = match token.t {
+                TokenType::Identifier => format!("unknown instruction or macro {}", token.value),
+                _ => format!("unexpected {}", token.value),
+            };
+            return Err(map.locate_error(error(token, message)));
+        }
         break;
     }
 
@@ -354,14 +450,11 @@ pub fn parse(source: &String) -> Vec<Instruction> {
     for inst in out.iter_mut() {
         match inst {
             Jmp(_, val) | Je(_, val) | Jg(_, val) | Jl(_, val) => {
+                // Anything else is left for the assembler, it may be a constant
                 if let Identifier(id) = val {
-                    let mut ip: i16 = 0;
-                    for (string, address) in &label_table {
-                        if *string == *id {
-                            ip = *address;
-                        }
+                    if let Some((_, address)) = label_table.iter().rev().find(|(l, _)| l == id) {
+                        *val = Immediate(*address);
                     }
-                    *val = Immediate(ip);
                 }
             }
 
@@ -371,9 +464,542 @@ pub fn parse(source: &String) -> Vec<Instruction> {
 
     out.retain(|x| if let Label(_, _) = x { false } else { true });
 
-    for inst in out.iter_mut() {
-        println!("{:?}", inst);
+    Ok((out, label_table, map))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn parse(source: &str) -> Result<Vec<Instruction>, String> {
+        Ok(parse_file(Path::new(""), source)?.0)
+    }
+
+    fn p(source: &str) -> Vec<Instruction> {
+        parse(source).unwrap()
+    }
+
+    // Writes the files of a test to a directory of its own, returning the path of
+    // the first one
+    fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hbot-{}-{}", test, std::process::id()));
+        for (name, source) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+        dir.join(files[0].0)
+    }
+
+    fn parse_files(
+        test: &str,
+        files: &[(&str, &str)],
+    ) -> Result<(Vec<Instruction>, SourceMap), String> {
+        let path = write_files(test, files);
+        let (instructions, _, source_map) = parse_file(&path, files[0].1)?;
+        Ok((instructions, source_map))
     }
 
-    out
+    fn reg(name: &str) -> Value {
+        Register(String::from(name))
+    }
+
+    #[test]
+    fn parses_instructions_without_operands() {
+        assert_eq!(
+            p("nop\nfwd\nrol\nror\nsht\nrld\nsee\nrad\nchk\nshd"),
+            vec![
+                Nop(0),
+                Fwd(1),
+                Rol(2),
+                Ror(3),
+                Sht(4),
+                Rld(5),
+                See(6),
+                Rad(7),
+                Chk(8),
+                Shd(9)
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_instructions_with_one_operand() {
+        assert_eq!(
+            p("jmp 1\nje 2\njg 3\njl 4\nnot a\nrnd b\nfnd 2\nsnd c\nrcv a"),
+            vec![
+                Jmp(0, Immediate(1)),
+                Je(1, Immediate(2)),
+                Jg(2, Immediate(3)),
+                Jl(3, Immediate(4)),
+                Not(4, reg("a")),
+                Rnd(5, reg("b")),
+                Fnd(6, Immediate(2)),
+                Snd(7, reg("c")),
+                Rcv(8, reg("a")),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_instructions_with_two_operands() {
+        assert_eq!(
+            p("mov a, 1\nadd b, a\nsub c, 2\ncmp dir, 0\nand a, b\nor a, 3\nxor c, c"),
+            vec![
+                Mov(0, reg("a"), Immediate(1)),
+                Add(1, reg("b"), reg("a")),
+                Sub(2, reg("c"), Immediate(2)),
+                Cmp(3, reg("dir"), Immediate(0)),
+                And(4, reg("a"), reg("b")),
+                Or(5, reg("a"), Immediate(3)),
+                Xor(6, reg("c"), reg("c")),
+            ]
+        );
+    }
+
+    #[test]
+    fn resolves_labels() {
+        let program = p("start:\n  fwd\nloop:\n  rol\n  jmp loop\n  je start\n  jg end\nend:");
+        assert_eq!(
+            program,
+            vec![
+                Fwd(1),
+                Rol(3),
+                Jmp(4, Immediate(1)),
+                Je(5, Immediate(0)),
+                Jg(6, Immediate(5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_labels_are_left_for_the_assembler() {
+        assert_eq!(
+            p("fwd\njmp nowhere"),
+            vec![Fwd(0), Jmp(1, Identifier(String::from("nowhere")))]
+        );
+    }
+
+    #[test]
+    fn labels_are_not_instructions() {
+        assert!(p("a1:\nb2:\n").is_empty());
+        assert_eq!(p("here: fwd"), vec![Fwd(0)]);
+    }
+
+    #[test]
+    fn rejects_missing_operands() {
+        let errors = [
+            ("fwd\nmov a\nrol", "Line 2: mov needs 2 operands, found 1"),
+            ("fwd\njmp\nrol", "Line 2: jmp needs 1 operand, found 0"),
+            (
+                "fwd\nadd a, 70000",
+                "Line 2: 70000 doesn't fit in a register",
+            ),
+            ("fwd a\nrol", "Line 1: unexpected a"),
+        ];
+        for (source, error) in errors {
+            assert_eq!(parse(source), Err(String::from(error)));
+        }
+    }
+
+    #[test]
+    fn ignores_comments() {
+        assert_eq!(
+            p("; start\nmain: ; loop\n  fwd ; move\n  jmp main"),
+            vec![Fwd(2), Jmp(3, Immediate(0))]
+        );
+    }
+
+    #[test]
+    fn replaces_constants() {
+        assert_eq!(
+            p(".equ SPEED 3\n.define LIMIT SPEED\nmov a, SPEED\ncmp a, limit\njmp START\n.equ START 0"),
+            vec![
+                Mov(2, reg("a"), Immediate(3)),
+                Cmp(3, reg("a"), Immediate(3)),
+                Jmp(4, Immediate(0))
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_constants() {
+        let errors = [
+            (".equ A 1", "Line 1: a can't be the name of a constant"),
+            (".equ X", "Line 1: .equ needs a name and a value"),
+            (".equ X\n1", "Line 1: .equ needs a name and a value"),
+            (".equ X 1\n.equ X 2", "Line 2: x is already defined"),
+            (".equ X Y", "Line 1: unknown constant y"),
+            (".equ X 70000", "Line 1: 70000 doesn't fit in a register"),
+            (".equ WIDTH 5", "Line 1: WIDTH is a predefined constant"),
+            ("fwd\nid:\nmov c, id", "Line 2: ID is a predefined constant"),
+            ("Height: fwd", "Line 1: HEIGHT is a predefined constant"),
+            (".equ X 1\nx:", "Line 2: x is already defined as a constant"),
+            (".set X 1", "Line 1: unknown directive .set"),
+        ];
+        for (source, error) in errors {
+            assert_eq!(parse(source), Err(String::from(error)));
+        }
+    }
+
+    #[test]
+    fn expands_macros() {
+        assert_eq!(
+            p(".macro fire turn back
+  turn
+  sht
+  rld
+  back
+.endm
+main: fire rol, ror
+fire ror rol
+jmp main"),
+            vec![
+                Rol(6),
+                Sht(6),
+                Rld(6),
+                Ror(6),
+                Ror(7),
+                Sht(7),
+                Rld(7),
+                Rol(7),
+                Jmp(8, Immediate(0))
+            ]
+        );
+    }
+
+    #[test]
+    fn macros_have_local_labels() {
+        assert_eq!(
+            p(".macro wait n
+  mov c, n
+loop:
+  sub c, 1
+  jg loop
+.endm
+loop:
+wait 2
+wait 3
+jmp loop"),
+            vec![
+                Mov(7, reg("c"), Immediate(2)),
+                Sub(7, reg("c"), Immediate(1)),
+                Jg(7, Immediate(1)),
+                Mov(8, reg("c"), Immediate(3)),
+                Sub(8, reg("c"), Immediate(1)),
+                Jg(8, Immediate(4)),
+                Jmp(9, Immediate(0))
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_macros() {
+        let errors = [
+            (
+                ".macro
+.endm",
+                "Line 1: .macro needs a name",
+            ),
+            (
+                ".macro m a
+.endm",
+                "Line 1: a can't be the name of a parameter",
+            ),
+            (
+                ".macro m
+fwd",
+                "Line 1: macro m has no .endm",
+            ),
+            (".endm", "Line 1: .endm without .macro"),
+            (
+                ".macro m
+.equ X 1
+.endm",
+                "Line 2: .equ can't be used inside a macro",
+            ),
+            (
+                ".macro m
+.endm
+.macro m
+.endm",
+                "Line 3: macro m is already defined",
+            ),
+            (
+                ".macro m x
+mov a, x
+.endm
+m",
+                "Line 4: macro m takes 1 argument",
+            ),
+            (
+                ".macro fire turn, back
+turn
+sht
+back
+.endm
+fire rol ror foo
+fwd",
+                "Line 6: macro fire takes 2 arguments",
+            ),
+            (
+                ".macro fire turn, back
+.endm
+fwd
+fier rol, ror
+fwd",
+                "Line 4: unknown instruction or macro fier",
+            ),
+            (
+                ".macro m
+m
+.endm
+fwd
+m",
+                "Line 5: macros are nested too deeply in m",
+            ),
+        ];
+        for (source, error) in errors {
+            assert_eq!(parse(source), Err(String::from(error)));
+        }
+    }
+
+    #[test]
+    fn includes_files() {
+        let (instructions, source_map) = parse_files(
+            "includes",
+            &[
+                (
+                    "main.hasm",
+                    "main:\njmp aim.face\n.include \"lib/aim.hasm\"",
+                ),
+                ("lib/aim.hasm", "; Aiming\nface:\n  rol\n  jmp face\n"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            instructions,
+            vec![Jmp(1, Immediate(1)), Rol(5), Jmp(6, Immediate(1))]
+        );
+        assert_eq!(source_map.locate(1), (0, 1));
+        assert_eq!(source_map.locate(5), (1, 2));
+        assert_eq!(source_map.describe(1), "Line 2");
+        assert!(source_map.describe(6).ends_with("aim.hasm, line 4"));
+    }
+
+    #[test]
+    fn labels_of_included_files_dont_collide() {
+        let (instructions, _) = parse_files(
+            "collide",
+            &[
+                ("main.hasm", "loop:\nfwd\njmp loop\n.include \"wait.hasm\""),
+                ("wait.hasm", "loop:\nnop\njmp loop"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            instructions,
+            vec![Fwd(1), Jmp(2, Immediate(0)), Nop(5), Jmp(6, Immediate(2))]
+        );
+    }
+
+    #[test]
+    fn includes_files_once() {
+        let (instructions, _) = parse_files(
+            "once",
+            &[
+                ("main.hasm", ".include \"a.hasm\"\n.include \"lib/b.hasm\""),
+                ("a.hasm", "fwd"),
+                ("lib/b.hasm", ".include \"../a.hasm\"\nrol"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(instructions, vec![Fwd(2), Rol(4)]);
+    }
+
+    #[test]
+    fn rejects_invalid_includes() {
+        let cycle = parse_files(
+            "cycle",
+            &[
+                ("a.hasm", "fwd\n.include \"b.hasm\""),
+                ("b.hasm", "rol\n\n.include \"a.hasm\""),
+            ],
+        );
+        let error = cycle.err().unwrap();
+        assert!(error.ends_with("a.hasm makes a cycle"), "{}", error);
+        assert!(error.contains("b.hasm, line 3: including"), "{}", error);
+
+        let missing = parse_files("missing", &[("a.hasm", "fwd\n.include \"b.hasm\"")]);
+        assert!(missing.err().unwrap().starts_with("Line 2: couldn't read"));
+
+        let unquoted = parse_files("unquoted", &[("a.hasm", ".include b.hasm")]);
+        assert_eq!(
+            unquoted.err(),
+            Some(String::from(
+                "Line 1: .include needs the path of a file between quotes, optionally followed by as and a name"
+            ))
+        );
+
+        let lexical = parse_files(
+            "lexical",
+            &[("a.hasm", ".include \"b.hasm\""), ("b.hasm", "fwd\n@")],
+        );
+        let error = lexical.err().unwrap();
+        assert!(error.ends_with("b.hasm, line 2, column 1: unexpected character '@'"));
+
+        let namesakes = parse_files(
+            "namesakes",
+            &[
+                (
+                    "a.hasm",
+                    ".include \"x/util.hasm\"\n.include \"y/util.hasm\"",
+                ),
+                ("x/util.hasm", "face:\n  rol"),
+                ("y/util.hasm", "fwd\nface:\n  ror"),
+            ],
+        );
+        let error = namesakes.err().unwrap();
+        assert!(error.starts_with("Line 2: "), "{}", error);
+        assert!(
+            error.ends_with(
+                "util.hasm are both included as util, give one of them another name with as"
+            ),
+            "{}",
+            error
+        );
+
+        for name in ["my-lib.hasm", "my_lib.hasm", "2d.hasm"] {
+            let source = format!(".include \"{}\"", name);
+            let invalid = parse_files("names", &[("a.hasm", &source), (name, "fwd")]);
+            let error = invalid.err().unwrap();
+            let hint = format!(
+                "can only contain letters and digits: use .include \"{}\" as NAME",
+                name
+            );
+            assert!(error.ends_with(&hint), "{}", error);
+        }
+        for name in ["2d", "my.lib", "a", "fwd"] {
+            let source = format!(".include \"b.hasm\" as {}", name);
+            let invalid = parse_files("as", &[("a.hasm", &source), ("b.hasm", "fwd")]);
+            let error = invalid.err().unwrap();
+            assert!(error.starts_with("Line 1: "), "{}", error);
+            assert!(
+                error.contains("can't be the name of an included file"),
+                "{}",
+                error
+            );
+        }
+        let keyword = parse_files(
+            "keyword",
+            &[("a.hasm", ".include \"b.hasm\" like b"), ("b.hasm", "fwd")],
+        );
+        assert!(keyword.is_err());
+    }
+
+    #[test]
+    fn includes_files_under_a_name() {
+        // Namesakes in different directories, and files whose name isn't an identifier
+        let (instructions, _) = parse_files(
+            "named",
+            &[
+                (
+                    "main.hasm",
+                    "jmp left.face\njmp right.face\njmp mylib.go\njmp geo.go
+.include \"x/util.hasm\" as left
+.include \"y/util.hasm\" as Right
+.include \"my_lib.hasm\" as mylib
+.include \"2d.hasm\" as geo",
+                ),
+                ("x/util.hasm", "face:\n  rol"),
+                ("y/util.hasm", "face:\n  ror"),
+                ("my_lib.hasm", "go:\n  fwd"),
+                ("2d.hasm", "go:\n  sht"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            instructions,
+            vec![
+                Jmp(0, Immediate(4)),
+                Jmp(1, Immediate(5)),
+                Jmp(2, Immediate(6)),
+                Jmp(3, Immediate(7)),
+                Rol(9),
+                Ror(11),
+                Fwd(13),
+                Sht(15),
+            ]
+        );
+    }
+
+    #[test]
+    fn includes_stay_in_the_directory_of_the_program() {
+        let dir = write_files("jail", &[("bot/main.hasm", ""), ("secret.hasm", "fwd")]);
+        let dir = dir.parent().unwrap().parent().unwrap();
+        let secret = dir.join("secret.hasm");
+        let sources = [
+            String::from(".include \"../secret.hasm\""),
+            format!(".include \"{}\"", secret.display()),
+            String::from(".include \"/dev/zero\""),
+        ];
+        for source in sources {
+            let error = parse_file(&dir.join("bot/main.hasm"), &source)
+                .err()
+                .unwrap();
+            assert!(
+                error.ends_with("can't be included, it's outside the directory of the program"),
+                "{}",
+                error
+            );
+        }
+
+        // Only regular files can be included
+        let path = write_files("devices", &[("main.hasm", ""), ("lib/aim.hasm", "fwd")]);
+        let error = parse_file(&path, ".include \"lib\"").err().unwrap();
+        assert!(
+            error.ends_with("lib can't be included, it's not a file"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn included_files_count_towards_the_size_of_the_program() {
+        let big = "nop\n".repeat(crate::robot::MAX_SOURCE_SIZE / 4);
+        let too_long = parse_files(
+            "size",
+            &[("main.hasm", ".include \"big.hasm\""), ("big.hasm", &big)],
+        );
+        let error = too_long.err().unwrap();
+        assert!(
+            error.starts_with("Line 1: program is too long with"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_labels() {
+        assert_eq!(
+            parse("loop:\nfwd\nloop:\nrol"),
+            Err(String::from("Line 3: label loop is already defined"))
+        );
+    }
+
+    #[test]
+    fn rejects_lexical_errors() {
+        assert!(parse("fwd\nsht @\nrol").is_err());
+    }
+
+    #[test]
+    fn reports_opcodes_and_operands() {
+        let inst = Add(3, reg("a"), Immediate(2));
+        assert_eq!(inst.line(), 3);
+        assert_eq!(inst.opcode(), "add");
+        assert_eq!(inst.operands(), vec![&reg("a"), &Immediate(2)]);
+        assert!(Fwd(0).operands().is_empty());
+    }
 }
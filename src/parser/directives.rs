@@ -0,0 +1,327 @@
+use crate::bytecode::Constant;
+
+use super::lexer::*;
+
+pub(super) fn error(token: &Token, message: String) -> String {
+    format!("Line {}: {}", token.span.line + 1, message)
+}
+
+// Value of an immediate. Registers hold 16 bits, so numbers from 32768 to 65535 wrap
+// around to negative values.
+pub(super) fn immediate(text: &str) -> Option<i16> {
+    text.parse::<u16>().ok().map(|n| n as i16)
+}
+
+pub(super) fn is_label(tokens: &[Token], i: usize) -> bool {
+    tokens[i].t == TokenType::Identifier
+        && tokens
+            .get(i + 1)
+            .is_some_and(|t| t.t == TokenType::Punctuator && t.value == ":")
+}
+
+// Most tokens macros can expand to, so that macros using each other can't make a
+// program grow without bounds
+const MAX_EXPANDED_TOKENS: usize = 1 << 17;
+
+// Most macros that can be expanded inside each other
+const MAX_MACRO_DEPTH: usize = 16;
+
+struct Macro {
+    name: String,
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+// Tokens of the same line following the one at i
+fn rest_of_line(tokens: &[Token], i: usize) -> Vec<&Token> {
+    tokens[i + 1..]
+        .iter()
+        .take_while(|t| t.span.line == tokens[i].span.line)
+        .collect()
+}
+
+// Macros calls are statements: they start a line or follow a label
+fn is_statement(tokens: &[Token], i: usize) -> bool {
+    i == 0
+        || tokens[i - 1].span.line != tokens[i].span.line
+        || (tokens[i - 1].value == ":" && i >= 2 && is_label(tokens, i - 2))
+}
+
+// Macros, defined with:
+//
+//   .macro NAME [PARAM, ...]
+//     ...
+//   .endm
+//
+// and used as instructions, with an argument for each parameter: NAME ARG, ... The
+// definitions are removed and every use of a macro is replaced by its body, with the
+// parameters replaced by the arguments. Labels defined inside a macro are local to
+// each use of it. Instructions coming from a macro belong to the line it's used on.
+pub fn expand_macros(tokens: Vec<Token>) -> Result<Vec<Token>, String> {
+    let mut macros: Vec<Macro> = vec![];
+    let mut rest = vec![];
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = &tokens[i];
+        if token.t == TokenType::Directive && token.value == ".endm" {
+            return Err(error(token, String::from(".endm without .macro")));
+        }
+        if token.t != TokenType::Directive || token.value != ".macro" {
+            rest.push(token.clone());
+            i += 1;
+            continue;
+        }
+
+        let header = rest_of_line(&tokens, i);
+        let name = match header.first() {
+            Some(name) if name.t == TokenType::Identifier => name.value.clone(),
+            _ => return Err(error(token, String::from(".macro needs a name"))),
+        };
+        if macros.iter().any(|m| m.name == name) {
+            return Err(error(token, format!("macro {} is already defined", name)));
+        }
+        let mut params = vec![];
+        for param in header[1..].iter().filter(|t| t.value != ",") {
+            if param.t != TokenType::Identifier {
+                return Err(error(
+                    token,
+                    format!("{} can't be the name of a parameter", param.value),
+                ));
+            }
+            params.push(param.value.clone());
+        }
+
+        i += 1 + header.len();
+        let start = i;
+        while i < tokens.len() && tokens[i].value != ".endm" {
+            if tokens[i].t == TokenType::Directive {
+                return Err(error(
+                    &tokens[i],
+                    format!("{} can't be used inside a macro", tokens[i].value),
+                ));
+            }
+            i += 1;
+        }
+        if i == tokens.len() {
+            return Err(error(token, format!("macro {} has no .endm", name)));
+        }
+        macros.push(Macro {
+            name,
+            params,
+            body: tokens[start..i].to_vec(),
+        });
+        i += 1;
+    }
+
+    let mut out = vec![];
+    let mut uses = 0;
+    expand(&rest, &macros, None, 0, &mut uses, &mut out)?;
+    Ok(out)
+}
+
+// Copies the tokens to out, expanding the macros used in them. Tokens coming from a
+// macro get the position of the use of the outermost macro.
+fn expand(
+    tokens: &[Token],
+    macros: &[Macro],
+    at: Option<Span>,
+    depth: usize,
+    uses: &mut usize,
+    out: &mut Vec<Token>,
+) -> Result<(), String> {
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = &tokens[i];
+        let span = at.unwrap_or(token.span);
+        let m = macros.iter().find(|m| m.name == token.value);
+        let m = match m {
+            Some(m)
+                if token.t == TokenType::Identifier
+                    && is_statement(tokens, i)
+                    && !is_label(tokens, i) =>
+            {
+                m
+            }
+            _ => {
+                let mut token = token.clone();
+                token.span = span;
+                out.push(token);
+                i += 1;
+                continue;
+            }
+        };
+
+        let position = Token {
+            value: token.value.clone(),
+            t: token.t,
+            span,
+        };
+        if depth >= MAX_MACRO_DEPTH {
+            return Err(error(
+                &position,
+                format!("macros are nested too deeply in {}", m.name),
+            ));
+        }
+
+        // Arguments follow on the same line, separated by commas. Since they can be
+        // opcodes, a macro takes as many tokens as it has parameters.
+        let line = rest_of_line(tokens, i);
+        let mut args: Vec<&Token> = vec![];
+        let mut j = 0;
+        while j < line.len() && args.len() < m.params.len() {
+            if line[j].t == TokenType::Directive {
+                break;
+            }
+            if line[j].value != "," {
+                args.push(line[j]);
+            }
+            j += 1;
+        }
+        if args.len() != m.params.len() || j < line.len() {
+            let plural = if m.params.len() == 1 { "" } else { "s" };
+            return Err(error(
+                &position,
+                format!(
+                    "macro {} takes {} argument{}",
+                    m.name,
+                    m.params.len(),
+                    plural
+                ),
+            ));
+        }
+        i += 1 + j;
+
+        // Every use gets its own copy of the labels of the macro
+        *uses += 1;
+        let labels: Vec<&String> = (0..m.body.len())
+            .filter(|j| is_label(&m.body, *j))
+            .map(|j| &m.body[j].value)
+            .collect();
+        let body: Vec<Token> = m
+            .body
+            .iter()
+            .map(|t| {
+                let mut t = t.clone();
+                if t.t == TokenType::Identifier {
+                    if let Some(p) = m.params.iter().position(|p| *p == t.value) {
+                        t.value = args[p].value.clone();
+                        t.t = args[p].t;
+                    } else if labels.contains(&&t.value) {
+                        t.value = format!("{}.{}.{}", m.name, uses, t.value);
+                    }
+                }
+                t
+            })
+            .collect();
+        expand(&body, macros, Some(span), depth + 1, uses, out)?;
+
+        if out.len() > MAX_EXPANDED_TOKENS {
+            return Err(error(
+                &position,
+                format!("macro {} expands to too many instructions", m.name),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Named constants, defined on a line of their own with either of:
+//
+//   .equ NAME VALUE
+//   .define NAME VALUE
+//
+// The value is a number or a constant defined before. Constants can be used anywhere
+// a number can, the directives are removed and every use of a constant is replaced
+// by its value.
+pub fn define_constants(tokens: Vec<Token>) -> Result<Vec<Token>, String> {
+    let mut constants: Vec<(String, String)> = vec![];
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = &tokens[i];
+        if token.t != TokenType::Directive {
+            i += 1;
+            continue;
+        }
+        if token.value != ".equ" && token.value != ".define" {
+            return Err(error(token, format!("unknown directive {}", token.value)));
+        }
+
+        let args: Vec<&Token> = tokens[i + 1..]
+            .iter()
+            .take_while(|t| t.span.line == token.span.line)
+            .collect();
+        if args.len() != 2 {
+            return Err(error(
+                token,
+                format!("{} needs a name and a value", token.value),
+            ));
+        }
+        let (name, value) = (args[0], args[1]);
+        if name.t != TokenType::Identifier {
+            return Err(error(
+                token,
+                format!("{} can't be the name of a constant", name.value),
+            ));
+        }
+        if Constant::from_name(&name.value).is_some() {
+            return Err(error(
+                token,
+                format!("{} is a predefined constant", name.value.to_uppercase()),
+            ));
+        }
+        if constants.iter().any(|(n, _)| *n == name.value) {
+            return Err(error(token, format!("{} is already defined", name.value)));
+        }
+
+        let value = match value.t {
+            TokenType::Immediate => value.value.clone(),
+            TokenType::Identifier => match constants.iter().find(|(n, _)| *n == value.value) {
+                Some((_, v)) => v.clone(),
+                None => return Err(error(token, format!("unknown constant {}", value.value))),
+            },
+            _ => return Err(error(token, format!("{} is not a number", value.value))),
+        };
+        if immediate(&value).is_none() {
+            return Err(error(token, format!("{} doesn't fit in a register", value)));
+        }
+        constants.push((name.value.clone(), value));
+        i += 3;
+    }
+
+    // Replace the uses of the constants, leaving out the directives
+    let mut out = vec![];
+    let mut skip = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        if skip > 0 {
+            skip -= 1;
+            continue;
+        }
+        if token.t == TokenType::Directive {
+            skip = 2;
+            continue;
+        }
+
+        if is_label(&tokens, i) && Constant::from_name(&token.value).is_some() {
+            return Err(error(
+                token,
+                format!("{} is a predefined constant", token.value.to_uppercase()),
+            ));
+        }
+        let constant = constants.iter().find(|(n, _)| *n == token.value);
+        match constant {
+            Some(_) if is_label(&tokens, i) => {
+                return Err(error(
+                    token,
+                    format!("{} is already defined as a constant", token.value),
+                ))
+            }
+            Some((_, value)) if token.t == TokenType::Identifier => out.push(Token {
+                value: value.clone(),
+                t: TokenType::Immediate,
+                span: token.span,
+            }),
+            _ => out.push(token.clone()),
+        }
+    }
+    Ok(out)
+}
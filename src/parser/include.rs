@@ -0,0 +1,293 @@
+use super::directives::{error, is_label};
+use super::lexer::*;
+use crate::robot::MAX_SOURCE_SIZE;
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+// File of a program
+pub struct SourceFile {
+    pub path: PathBuf,
+    pub source: String,
+    pub first_line: usize, // Line of the program the file starts at
+}
+
+// Files a program is made of: the file it's read from and the ones it includes. Every
+// file gets its own range of lines of the program, after the ones of the files before
+// it, so the line of an instruction also tells the file it comes from.
+#[derive(Default)]
+pub struct SourceMap {
+    pub files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    // Index of the file a line of the program is in, and the line in that file
+    pub fn locate(&self, line: usize) -> (usize, usize) {
+        let file = self
+            .files
+            .iter()
+            .rposition(|f| f.first_line <= line)
+            .unwrap_or(0);
+        let first_line = self.files.get(file).map_or(0, |f| f.first_line);
+        (file, line - first_line)
+    }
+
+    // Line of the program as shown in messages, along with the file for lines of
+    // included files
+    pub fn describe(&self, line: usize) -> String {
+        match self.locate(line) {
+            (0, line) => format!("Line {}", line + 1),
+            (file, line) => format!("{}, line {}", self.files[file].path.display(), line + 1),
+        }
+    }
+
+    // Makes an error starting with a line of the program point to its file
+    pub fn locate_error(&self, error: String) -> String {
+        let rest = match error.strip_prefix("Line ") {
+            Some(rest) => rest,
+            None => return error,
+        };
+        let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+        match rest[..digits].parse::<usize>() {
+            Ok(line) if line > 0 => format!("{}{}", self.describe(line - 1), &rest[digits..]),
+            _ => error,
+        }
+    }
+}
+
+struct Includer {
+    map: SourceMap,
+    root: PathBuf,              // Directory of the program, includes stay in it
+    stack: Vec<PathBuf>,        // Files being included, each by the one before it
+    included: HashSet<PathBuf>, // Files included so far
+    size: usize,                // Bytes of all the files
+    next_line: usize,
+    namespaces: HashMap<String, PathBuf>, // File included under each namespace
+}
+
+// Namespaces go in front of labels, so they have to be identifiers without dots
+fn valid_namespace(name: &str) -> bool {
+    name.chars().all(|c| c.is_alphanumeric())
+        && name.chars().next().is_some_and(|c| !c.is_ascii_digit())
+}
+
+impl Includer {
+    // Tokens of a file, with the files it includes. Labels of included files are
+    // prefixed by the namespace.
+    fn file(
+        &mut self,
+        path: PathBuf,
+        source: String,
+        namespace: Option<String>,
+    ) -> Result<Vec<Token>, String> {
+        let first_line = self.next_line;
+        self.next_line += source.split('\n').count();
+        self.map.files.push(SourceFile {
+            path: path.clone(),
+            source,
+            first_line,
+        });
+        let mut tokens = lex_from(&self.map.files.last().unwrap().source, first_line)?;
+        tokens.retain(|t| t.t != TokenType::Comment);
+
+        if let Some(namespace) = namespace {
+            let labels: HashSet<String> = (0..tokens.len())
+                .filter(|i| is_label(&tokens, *i))
+                .map(|i| tokens[i].value.clone())
+                .collect();
+            for token in tokens.iter_mut() {
+                if token.t == TokenType::Identifier && labels.contains(&token.value) {
+                    token.value = format!("{}.{}", namespace, token.value);
+                }
+            }
+        }
+
+        let mut out = vec![];
+        let mut i = 0;
+        while i < tokens.len() {
+            let token = &tokens[i];
+            if token.t != TokenType::Directive || token.value != ".include" {
+                out.push(token.clone());
+                i += 1;
+                continue;
+            }
+            let args: Vec<&Token> = tokens[i + 1..]
+                .iter()
+                .take_while(|t| t.span.line == token.span.line)
+                .collect();
+            let namespace = match args.as_slice() {
+                [file] if file.t == TokenType::Text => None,
+                [file, keyword, name @ ..]
+                    if file.t == TokenType::Text
+                        && keyword.t == TokenType::Identifier
+                        && keyword.value == "as" =>
+                {
+                    match name {
+                        [name] if name.t == TokenType::Identifier && valid_namespace(&name.value) => {
+                            Some(name.value.clone())
+                        }
+                        _ => {
+                            let name: Vec<&str> = name.iter().map(|t| t.value.as_str()).collect();
+                            return Err(error(
+                                token,
+                                format!(
+                                    "{} can't be the name of an included file, it can only contain letters and digits",
+                                    name.join(" ")
+                                ),
+                            ));
+                        }
+                    }
+                }
+                _ => {
+                    return Err(error(
+                        token,
+                        String::from(
+                            ".include needs the path of a file between quotes, optionally followed by as and a name",
+                        ),
+                    ))
+                }
+            };
+            out.extend(self.include(&path, &args[0].value, namespace, token)?);
+            i += 1 + args.len();
+        }
+        Ok(out)
+    }
+
+    fn include(
+        &mut self,
+        from: &Path,
+        name: &str,
+        namespace: Option<String>,
+        token: &Token,
+    ) -> Result<Vec<Token>, String> {
+        let path = from.parent().unwrap_or(Path::new("")).join(name);
+        let unreadable =
+            |e: std::io::Error| error(token, format!("couldn't read {}: {}", path.display(), e));
+        let canonical = fs::canonicalize(&path).map_err(unreadable)?;
+
+        // Programs can come from anyone, so they can only include their own libraries,
+        // and only regular files: devices like /dev/zero never end
+        if !canonical.starts_with(&self.root) {
+            return Err(error(
+                token,
+                format!(
+                    "{} can't be included, it's outside the directory of the program",
+                    path.display()
+                ),
+            ));
+        }
+        if !fs::metadata(&canonical).map_err(unreadable)?.is_file() {
+            return Err(error(
+                token,
+                format!("{} can't be included, it's not a file", path.display()),
+            ));
+        }
+
+        if self.stack.contains(&canonical) {
+            return Err(error(
+                token,
+                format!("including {} makes a cycle", path.display()),
+            ));
+        }
+        if !self.included.insert(canonical.clone()) {
+            return Ok(vec![]);
+        }
+
+        // The size of the file could change after checking it, so reading stops past
+        // the maximum
+        let mut source = String::new();
+        fs::File::open(&canonical)
+            .and_then(|f| {
+                f.take(MAX_SOURCE_SIZE as u64 + 1)
+                    .read_to_string(&mut source)
+            })
+            .map_err(unreadable)?;
+        self.size += source.len();
+        if self.size > MAX_SOURCE_SIZE {
+            return Err(error(
+                token,
+                format!(
+                    "program is too long with {}, the maximum is {} bytes",
+                    path.display(),
+                    MAX_SOURCE_SIZE
+                ),
+            ));
+        }
+
+        // Without a name given with as, the name of the file goes in front of its
+        // labels, so it has to be an identifier
+        let namespace = match namespace {
+            Some(namespace) => namespace,
+            None => {
+                let stem = path
+                    .file_stem()
+                    .map_or(String::new(), |stem| stem.to_string_lossy().to_lowercase());
+                if !valid_namespace(&stem) {
+                    return Err(error(
+                        token,
+                        format!(
+                            "{} needs a name, the name of the file can only contain letters and digits: use .include \"{}\" as NAME",
+                            path.display(),
+                            name
+                        ),
+                    ));
+                }
+                stem
+            }
+        };
+        if let Some(other) = self.namespaces.get(&namespace) {
+            return Err(error(
+                token,
+                format!(
+                    "{} and {} are both included as {}, give one of them another name with as",
+                    other.display(),
+                    path.display(),
+                    namespace
+                ),
+            ));
+        }
+        self.namespaces.insert(namespace.clone(), path.clone());
+        self.stack.push(canonical);
+        let tokens = self.file(path, source, Some(namespace))?;
+        self.stack.pop();
+        Ok(tokens)
+    }
+}
+
+// Lexes a program along with the files it includes with:
+//
+//   .include "PATH"
+//   .include "PATH" as NAME
+//
+// where the path is relative to the file including it, and has to be in the directory
+// of the program or below it. The tokens of the included file take the place of the
+// directive. Every file is included once, however many files include it, and files
+// can't include each other in a cycle. Labels of included files are prefixed by the
+// name given with as, or else by the name of the file, so label track of lib/aim.hasm
+// is aim.track. Names have to be valid identifiers, and different files can't share
+// one. Comments are left out.
+pub fn include_files(path: &Path, source: &str) -> Result<(Vec<Token>, SourceMap), String> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut includer = Includer {
+        map: SourceMap::default(),
+        root: fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf()),
+        stack: vec![],
+        included: HashSet::new(),
+        size: source.len(),
+        next_line: 0,
+        namespaces: HashMap::new(),
+    };
+    if let Ok(canonical) = fs::canonicalize(path) {
+        includer.stack.push(canonical.clone());
+        includer.included.insert(canonical);
+    }
+    match includer.file(path.to_path_buf(), source.to_string(), None) {
+        Ok(tokens) => Ok((tokens, includer.map)),
+        Err(e) => Err(includer.map.locate_error(e)),
+    }
+}
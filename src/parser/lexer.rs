@@ -1,18 +1,33 @@
-#[derive(Debug, PartialEq)]
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TokenType {
     Opcode,
     Register,
     Immediate,
     Punctuator,
     Identifier,
+    Comment,
+    Directive,
+    Text,
+}
+
+// Position of a token in the source. Lines and columns start from 0 and columns
+// count characters, not bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Span {
+    pub offset: usize, // Bytes from the start of the source
+    pub line: usize,
+    pub column: usize,
 }
 
 // Lexical token
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Token {
     pub value: String,
     pub t: TokenType,
-    pub line: usize,
+    pub span: Span,
 }
 
 impl PartialEq for Token {
@@ -21,172 +36,371 @@ impl PartialEq for Token {
     }
 }
 
-fn lex_immediate(source: &String, i: &mut usize) -> Option<Token> {
-    let mut token = Token {
-        value: String::from(""),
-        t: TokenType::Immediate,
-        line: 0,
-    };
-
-    loop {
-        let char = source.chars().nth(*i);
-        if let Some(c) = char {
-            if c.is_digit(10) {
-                token.value.push(c);
-                *i += 1;
-                continue;
-            } else if token.value == "" {
-                return None;
-            }
+const OPCODES: &[&str] = &[
+    "nop", "mov", "fwd", "rol", "ror", "sht", "rld", "see", "jmp", "je", "jg", "jl", "add", "sub",
+    "cmp", "and", "or", "xor", "not", "rnd", "rad", "chk", "fnd", "shd", "snd", "rcv",
+];
+
+const REGISTERS: &[&str] = &[
+    "a", "b", "c", "dir", "cx", "cy", "ip", "energy", "loaded", "shield", "msg",
+];
+
+// Walks the source a character at a time, so lexing is linear in the size of the
+// source whatever characters it contains
+struct Lexer<'a> {
+    chars: Peekable<CharIndices<'a>>,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(source: &'a str, first_line: usize) -> Lexer<'a> {
+        Lexer {
+            chars: source.char_indices().peekable(),
+            line: first_line,
+            column: 0,
         }
-        return Some(token);
     }
-}
 
-fn lex_identifier(source: &String, i: &mut usize) -> Option<Token> {
-    let mut j = *i;
-    let mut token = Token {
-        value: String::from(""),
-        t: TokenType::Identifier,
-        line: 0,
-    };
-
-    loop {
-        let char = source.chars().nth(j);
-        if let Some(c) = char {
-            if c.is_alphanumeric() {
-                token.value.push(c);
-                j += 1;
-                continue;
-            } else if token.value == "" {
-                return None;
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().map(|(_, c)| *c)
+    }
+
+    fn span(&mut self) -> Option<Span> {
+        let offset = self.chars.peek()?.0;
+        Some(Span {
+            offset,
+            line: self.line,
+            column: self.column,
+        })
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let (_, c) = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 0;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    // Consumes characters as long as they satisfy the predicate
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> String {
+        let mut value = String::new();
+        while let Some(c) = self.peek() {
+            if !predicate(c) {
+                break;
             }
+            value.push(c);
+            self.advance();
         }
-        *i = j;
-        return Some(token);
+        value
     }
-}
 
-fn lex_punctuator(source: &String, i: &mut usize) -> Option<Token> {
-    let mut j = *i;
-    let mut token = Token {
-        value: String::from(""),
-        t: TokenType::Punctuator,
-        line: 0,
-    };
-
-    let punctuators = vec![String::from(","), String::from(":")];
-
-    let char = source.chars().nth(j);
-    if let Some(c) = char {
-        token.value.push(c);
-        if punctuators.contains(&token.value) {
-            j += 1;
-        } else if token.value == "" {
-            return None;
+    // Next token of the source, or None at the end of it
+    fn token(&mut self) -> Result<Option<Token>, String> {
+        self.take_while(|c| c.is_whitespace());
+        let (span, c) = match (self.span(), self.peek()) {
+            (Some(span), Some(c)) => (span, c),
+            _ => return Ok(None),
+        };
+
+        // Immediate values
+        if c.is_ascii_digit() {
+            let value = self.take_while(|c| c.is_ascii_digit());
+            return Ok(Some(Token {
+                value,
+                t: TokenType::Immediate,
+                span,
+            }));
+        }
+
+        // Identifiers, which can contain any letter or digit, not just ASCII ones. Dots
+        // separate the name of an included file from the name of one of its labels.
+        if c.is_alphanumeric() {
+            let value = self
+                .take_while(|c| c.is_alphanumeric() || c == '.')
+                .to_lowercase();
+            let t = if REGISTERS.contains(&value.as_str()) {
+                TokenType::Register
+            } else if OPCODES.contains(&value.as_str()) {
+                TokenType::Opcode
+            } else {
+                TokenType::Identifier
+            };
+            return Ok(Some(Token { value, t, span }));
+        }
+
+        // Assembler directives, like .equ
+        if c == '.' {
+            self.advance();
+            let name = self.take_while(|c| c.is_alphanumeric()).to_lowercase();
+            if name.is_empty() {
+                return Err(format!(
+                    "Line {}, column {}: expected the name of a directive after .",
+                    span.line + 1,
+                    span.column + 1
+                ));
+            }
+            return Ok(Some(Token {
+                value: format!(".{}", name),
+                t: TokenType::Directive,
+                span,
+            }));
+        }
+
+        // Comments, from ; to the end of the line
+        if c == ';' {
+            let value = self.take_while(|c| c != '\n').trim_end().to_string();
+            return Ok(Some(Token {
+                value,
+                t: TokenType::Comment,
+                span,
+            }));
         }
+
+        // Strings, like the name of an included file, between quotes on a single line
+        if c == '"' {
+            self.advance();
+            let value = self.take_while(|c| c != '"' && c != '\n');
+            if self.advance() != Some('"') {
+                return Err(format!(
+                    "Line {}, column {}: string without closing quote",
+                    span.line + 1,
+                    span.column + 1
+                ));
+            }
+            return Ok(Some(Token {
+                value,
+                t: TokenType::Text,
+                span,
+            }));
+        }
+
+        // Punctuators
+        self.advance();
+        if c != ',' && c != ':' {
+            return Err(format!(
+                "Line {}, column {}: unexpected character {:?}",
+                span.line + 1,
+                span.column + 1,
+                c
+            ));
+        }
+        Ok(Some(Token {
+            value: c.to_string(),
+            t: TokenType::Punctuator,
+            span,
+        }))
     }
-    *i = j;
-    return Some(token);
 }
 
-pub fn lex(source: &String) -> Vec<Token> {
-    let mut i = 0;
+// Splits the source into tokens, failing at the first character that can't start one
+pub fn lex(source: &str) -> Result<Vec<Token>, String> {
+    lex_from(source, 0)
+}
+
+// Like lex, numbering the lines of the source from first_line
+pub fn lex_from(source: &str, first_line: usize) -> Result<Vec<Token>, String> {
+    let mut lexer = Lexer::new(source, first_line);
     let mut tokens: Vec<Token> = vec![];
+    while let Some(token) = lexer.token()? {
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
 
-    let opcodes = vec![
-        String::from("nop"),
-        String::from("mov"),
-        // Motor
-        String::from("fwd"),
-        String::from("rol"),
-        String::from("ror"),
-        // Turret
-        String::from("sht"),
-        String::from("rld"),
-        // Vision
-        String::from("see"),
-        // Control flow
-        String::from("jmp"),
-        String::from("je"),
-        String::from("jg"),
-        String::from("jl"),
-        // Arithmetic and logic
-        String::from("add"),
-        String::from("sub"),
-        String::from("cmp"),
-        String::from("and"),
-        String::from("or"),
-        String::from("xor"),
-        String::from("not"),
-        // Sensors
-        String::from("rad"),
-        String::from("chk"),
-    ];
-
-    let registers = vec![
-        String::from("a"),
-        String::from("b"),
-        String::from("c"),
-        String::from("dir"),
-        String::from("cx"),
-        String::from("cy"),
-        String::from("ip"),
-    ];
-
-    let mut current_line = 0;
-    loop {
-        // Eat white space
-        loop {
-            let char = source.chars().nth(i);
-            if let Some(c) = char {
-                if c == '\n' {
-                    current_line += 1;
-                }
-                if c.is_whitespace() {
-                    i += 1;
-                    continue;
-                }
-            }
-            break;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn types(source: &str) -> Vec<TokenType> {
+        lex(source).unwrap().into_iter().map(|t| t.t).collect()
+    }
+
+    fn values(source: &str) -> Vec<String> {
+        lex(source).unwrap().into_iter().map(|t| t.value).collect()
+    }
+
+    #[test]
+    fn lexes_every_opcode() {
+        let opcodes = [
+            "nop", "mov", "fwd", "rol", "ror", "sht", "rld", "see", "jmp", "je", "jg", "jl", "add",
+            "sub", "cmp", "and", "or", "xor", "not", "rnd", "rad", "chk", "fnd", "shd", "snd",
+            "rcv",
+        ];
+        for opcode in opcodes {
+            let tokens = lex(opcode).unwrap();
+            assert_eq!(tokens.len(), 1, "{}", opcode);
+            assert_eq!(tokens[0].t, TokenType::Opcode, "{}", opcode);
+            assert_eq!(tokens[0].value, opcode);
         }
+    }
 
-        // Lex next token
-        let char = source.chars().nth(i);
-        if let Some(_) = char {
-            // Immediate values
-            let tmp = lex_immediate(&source, &mut i);
-            if let Some(mut token) = tmp {
-                token.line = current_line;
-                tokens.push(token);
-                continue;
-            }
+    #[test]
+    fn lexes_every_register() {
+        let registers = [
+            "a", "b", "c", "dir", "cx", "cy", "ip", "energy", "loaded", "shield", "msg",
+        ];
+        for register in registers {
+            let tokens = lex(register).unwrap();
+            assert_eq!(tokens.len(), 1, "{}", register);
+            assert_eq!(tokens[0].t, TokenType::Register, "{}", register);
+            assert_eq!(tokens[0].value, register);
+        }
+    }
 
-            // Identifiers
-            let tmp = lex_identifier(&source, &mut i);
-            if let Some(mut token) = tmp {
-                token.value = token.value.to_lowercase();
-                token.line = current_line;
-                if opcodes.contains(&token.value) {
-                    token.t = TokenType::Opcode;
-                }
-
-                if registers.contains(&token.value) {
-                    token.t = TokenType::Register;
-                }
-
-                tokens.push(token);
-                continue;
-            }
+    #[test]
+    fn lexes_punctuators() {
+        assert_eq!(types(","), vec![TokenType::Punctuator]);
+        assert_eq!(types(":"), vec![TokenType::Punctuator]);
+        assert_eq!(values("a,b:"), vec!["a", ",", "b", ":"]);
+    }
 
-            // Punctuators
-            let tmp = lex_punctuator(&source, &mut i);
-            if let Some(mut token) = tmp {
-                token.line = current_line;
-                tokens.push(token);
-                continue;
-            }
+    #[test]
+    fn lexes_immediates() {
+        assert_eq!(types("0 42 32767"), vec![TokenType::Immediate; 3]);
+        assert_eq!(values("0 42 32767"), vec!["0", "42", "32767"]);
+    }
+
+    #[test]
+    fn lexes_identifiers() {
+        assert_eq!(types("loop label2"), vec![TokenType::Identifier; 2]);
+        assert_eq!(values("loop label2"), vec!["loop", "label2"]);
+    }
+
+    #[test]
+    fn opcodes_and_registers_are_case_insensitive() {
+        assert_eq!(
+            types("MOV A, Dir"),
+            vec![
+                TokenType::Opcode,
+                TokenType::Register,
+                TokenType::Punctuator,
+                TokenType::Register
+            ]
+        );
+        assert_eq!(values("MOV A, Dir"), vec!["mov", "a", ",", "dir"]);
+    }
+
+    #[test]
+    fn lexes_an_instruction() {
+        assert_eq!(
+            types("add a, 10"),
+            vec![
+                TokenType::Opcode,
+                TokenType::Register,
+                TokenType::Punctuator,
+                TokenType::Immediate
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_track_of_lines() {
+        let tokens = lex("main:\n  fwd\n\n  jmp main\n").unwrap();
+        let lines: Vec<usize> = tokens.iter().map(|t| t.span.line).collect();
+        assert_eq!(lines, vec![0, 0, 1, 3, 3]);
+    }
+
+    #[test]
+    fn keeps_track_of_spans() {
+        let tokens = lex("main:\n  jmp main").unwrap();
+        let spans: Vec<(usize, usize, usize)> = tokens
+            .iter()
+            .map(|t| (t.span.offset, t.span.line, t.span.column))
+            .collect();
+        assert_eq!(spans, vec![(0, 0, 0), (4, 0, 4), (8, 1, 2), (12, 1, 6)]);
+    }
+
+    #[test]
+    fn lexes_unicode_identifiers() {
+        let tokens = lex("épée: jmp ÉPÉE").unwrap();
+        assert_eq!(tokens[0].t, TokenType::Identifier);
+        assert_eq!(tokens[0].value, "épée");
+        assert_eq!(tokens[3].value, "épée");
+        // Offsets are in bytes, columns in characters
+        assert_eq!((tokens[1].span.offset, tokens[1].span.column), (6, 4));
+        assert_eq!((tokens[3].span.offset, tokens[3].span.column), (12, 10));
+    }
+
+    #[test]
+    fn lexes_large_programs() {
+        let source = "loop:\n  add a, 1\n  jmp loop\n".repeat(2000);
+        let tokens = lex(&source).unwrap();
+        assert_eq!(tokens.len(), 8 * 2000);
+        assert_eq!(tokens.last().unwrap().span.line, 3 * 2000 - 1);
+    }
+
+    #[test]
+    fn reports_unknown_characters() {
+        assert_eq!(
+            lex("mov a, b\ncmp a, -1"),
+            Err(String::from("Line 2, column 8: unexpected character '-'"))
+        );
+        for source in ["[", "fwd ]", "rol#", "é:\n\t@"] {
+            assert!(lex(source).is_err(), "{}", source);
         }
-        return tokens;
+    }
+
+    #[test]
+    fn lexes_comments() {
+        assert_eq!(
+            types("; aim\nsht ; fire \nrld;"),
+            vec![
+                TokenType::Comment,
+                TokenType::Opcode,
+                TokenType::Comment,
+                TokenType::Opcode,
+                TokenType::Comment
+            ]
+        );
+        assert_eq!(values("sht ; Fire, now: \n"), vec!["sht", "; Fire, now:"]);
+    }
+
+    #[test]
+    fn lexes_directives() {
+        assert_eq!(
+            types(".equ WIDTH 10"),
+            vec![
+                TokenType::Directive,
+                TokenType::Identifier,
+                TokenType::Immediate
+            ]
+        );
+        assert_eq!(values(".EQU width 10"), vec![".equ", "width", "10"]);
+        assert!(lex("mov a, . 1").is_err());
+    }
+
+    #[test]
+    fn lexes_strings() {
+        assert_eq!(
+            types(".include \"lib/Aim.hasm\""),
+            vec![TokenType::Directive, TokenType::Text]
+        );
+        assert_eq!(values("\"lib/Aim.hasm\""), vec!["lib/Aim.hasm"]);
+        assert_eq!(
+            lex("fwd\n.include \"aim.hasm\nrol"),
+            Err(String::from(
+                "Line 2, column 10: string without closing quote"
+            ))
+        );
+    }
+
+    #[test]
+    fn lexes_labels_of_included_files() {
+        assert_eq!(
+            types("jmp Aim.track"),
+            vec![TokenType::Opcode, TokenType::Identifier]
+        );
+        assert_eq!(values("jmp Aim.track"), vec!["jmp", "aim.track"]);
+    }
+
+    #[test]
+    fn ignores_white_space() {
+        assert_eq!(values(" \t fwd \r\n\t rol  "), vec!["fwd", "rol"]);
+        assert!(lex("   \n\n ").unwrap().is_empty());
     }
 }
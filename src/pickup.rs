@@ -0,0 +1,65 @@
+use crate::robot::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PickupKind {
+    Ammo,
+    Health,
+    Energy,
+}
+
+// Crate lying on the field, collected by the robot moving onto its tile
+#[derive(Debug, Clone)]
+pub struct Pickup {
+    pub x: i32,
+    pub y: i32,
+    pub kind: PickupKind,
+}
+
+pub const AMMO_PICKUP: i32 = 20;
+pub const HEALTH_PICKUP: i32 = 25;
+pub const ENERGY_PICKUP: i32 = 50;
+
+// Random crates stop spawning when there are this many crates on the field
+pub const MAX_RANDOM_PICKUPS: usize = 5;
+
+impl PickupKind {
+    pub fn from_name(name: &str) -> Option<PickupKind> {
+        match name {
+            "ammo" => Some(PickupKind::Ammo),
+            "health" => Some(PickupKind::Health),
+            "energy" => Some(PickupKind::Energy),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PickupKind::Ammo => "ammo",
+            PickupKind::Health => "health",
+            PickupKind::Energy => "energy",
+        }
+    }
+
+    // Number used by the fnd instruction to select the kind of crate
+    pub fn id(&self) -> i16 {
+        match self {
+            PickupKind::Ammo => 1,
+            PickupKind::Health => 2,
+            PickupKind::Energy => 3,
+        }
+    }
+}
+
+impl Pickup {
+    pub fn apply(&self, robot: &mut Robot) {
+        match self.kind {
+            PickupKind::Ammo => robot.bullets += AMMO_PICKUP,
+            PickupKind::Health => {
+                robot.health = std::cmp::min(robot.max_health, robot.health + HEALTH_PICKUP)
+            }
+            PickupKind::Energy => {
+                robot.energy = std::cmp::min(robot.max_energy, robot.energy + ENERGY_PICKUP)
+            }
+        }
+    }
+}
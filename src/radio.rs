@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+
+// Maximum number of messages kept in the queue of each team. When the queue is full,
+// the oldest message is dropped.
+pub const RADIO_QUEUE_SIZE: usize = 8;
+
+pub struct Message {
+    pub id: u64,
+    pub tick: u32, // Tick the message was sent on
+    pub sender: usize,
+    pub sender_name: String,
+    pub value: i16,
+}
+
+// Message queues shared by the members of each team. Every robot reads the queue of
+// its team at its own pace, keeping track of the next message it hasn't read yet.
+// Messages are only added to the queues at the end of a tick, so they can be read
+// starting from the following tick.
+#[derive(Default)]
+pub struct TeamRadio {
+    pub queues: HashMap<i32, VecDeque<Message>>,
+    next_id: u64,
+}
+
+impl TeamRadio {
+    pub fn new() -> TeamRadio {
+        TeamRadio::default()
+    }
+
+    pub fn send(&mut self, team: i32, sender: usize, sender_name: &str, value: i16, tick: u32) {
+        let queue = self.queues.entry(team).or_default();
+        if queue.len() >= RADIO_QUEUE_SIZE {
+            queue.pop_front();
+        }
+        queue.push_back(Message {
+            id: self.next_id,
+            tick,
+            sender,
+            sender_name: String::from(sender_name),
+            value,
+        });
+        self.next_id += 1;
+    }
+
+    // Messages sent by teammates that the robot hasn't read yet, oldest first
+    pub fn pending(&self, team: i32, robot: usize, cursor: u64) -> Vec<&Message> {
+        match self.queues.get(&team) {
+            Some(queue) => queue
+                .iter()
+                .filter(|m| m.id >= cursor && m.sender != robot)
+                .collect(),
+            None => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(messages: Vec<&Message>) -> Vec<i16> {
+        messages.iter().map(|m| m.value).collect()
+    }
+
+    #[test]
+    fn teams_only_hear_their_teammates() {
+        let mut radio = TeamRadio::new();
+        radio.send(1, 0, "a", 10, 0);
+        radio.send(2, 1, "b", 20, 0);
+        radio.send(1, 2, "c", 30, 0);
+        assert_eq!(values(radio.pending(1, 5, 0)), vec![10, 30]);
+        assert_eq!(values(radio.pending(2, 5, 0)), vec![20]);
+        assert!(radio.pending(3, 5, 0).is_empty());
+
+        // Senders don't hear themselves
+        assert_eq!(values(radio.pending(1, 0, 0)), vec![30]);
+    }
+
+    #[test]
+    fn robots_read_at_their_own_pace() {
+        let mut radio = TeamRadio::new();
+        for value in 0..3 {
+            radio.send(1, 0, "a", value, 0);
+        }
+        let first = radio.pending(1, 1, 0)[0].id;
+        assert_eq!(values(radio.pending(1, 1, first + 1)), vec![1, 2]);
+        assert_eq!(values(radio.pending(1, 2, first)), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn full_queues_drop_the_oldest_message() {
+        let mut radio = TeamRadio::new();
+        for value in 0..RADIO_QUEUE_SIZE as i16 + 3 {
+            radio.send(1, 0, "a", value, 0);
+        }
+        radio.send(2, 0, "a", 99, 0);
+        let pending = values(radio.pending(1, 1, 0));
+        assert_eq!(pending.len(), RADIO_QUEUE_SIZE);
+        assert_eq!(pending[0], 3);
+        assert_eq!(*pending.last().unwrap(), RADIO_QUEUE_SIZE as i16 + 2);
+        assert_eq!(radio.queues[&2].len(), 1);
+    }
+}
@@ -0,0 +1,223 @@
+use crate::component::*;
+use crate::config::*;
+use crate::json::*;
+use crate::robot::*;
+
+fn pair(p: (i32, i32)) -> Json {
+    Json::Array(vec![Json::Int(p.0 as i64), Json::Int(p.1 as i64)])
+}
+
+fn robot_config_json(r: &RobotConfig) -> Json {
+    let components = match &r.components {
+        Some(names) => names.iter().map(|n| Json::str(n)).collect(),
+        None => default_loadout()
+            .iter()
+            .map(|c| Json::str(c.name()))
+            .collect(),
+    };
+    Json::object(vec![
+        ("name", Json::str(&r.name)),
+        ("program", Json::str(&r.program)),
+        ("position", pair((r.x, r.y))),
+        ("direction", pair(r.direction)),
+        ("team", Json::Int(r.team as i64)),
+        ("components", Json::Array(components)),
+        (
+            "weapon",
+            Json::object(vec![
+                ("reload", Json::Int(r.weapon.reload_ticks as i64)),
+                ("damage", Json::Int(r.weapon.damage as i64)),
+                ("range", Json::Int(r.weapon.range as i64)),
+                ("magazine", Json::Int(r.weapon.magazine_size as i64)),
+            ]),
+        ),
+        (
+            "armor",
+            Json::object(vec![
+                ("front", Json::Int(r.armor.front as i64)),
+                ("side", Json::Int(r.armor.side as i64)),
+                ("rear", Json::Int(r.armor.rear as i64)),
+            ]),
+        ),
+    ])
+}
+
+fn config_json(config: &MatchConfig) -> Json {
+    let pickups = config
+        .pickups
+        .iter()
+        .map(|p| {
+            Json::object(vec![
+                ("kind", Json::str(p.kind.name())),
+                ("position", pair((p.x, p.y))),
+            ])
+        })
+        .collect();
+    Json::object(vec![
+        ("field", pair((config.width, config.height))),
+        ("max_ticks", Json::Int(config.max_ticks as i64)),
+        ("shuffle_spawns", Json::Bool(config.shuffle_spawns)),
+        ("pickups", Json::Array(pickups)),
+        ("pickup_rate", Json::Int(config.pickup_rate as i64)),
+        (
+            "robots",
+            Json::Array(config.robots.iter().map(robot_config_json).collect()),
+        ),
+    ])
+}
+
+fn robot_json(r: &RobotSummary) -> Json {
+    Json::object(vec![
+        ("name", Json::str(&r.name)),
+        ("team", Json::Int(r.team as i64)),
+        ("alive", Json::Bool(r.health > 0)),
+        ("position", pair((r.x, r.y))),
+        ("health", Json::Int(r.health as i64)),
+        ("bullets", Json::Int(r.bullets as i64)),
+        ("energy", Json::Int(r.energy as i64)),
+        ("shots_fired", Json::Int(r.stats.shots_fired as i64)),
+        ("shots_hit", Json::Int(r.stats.shots_hit as i64)),
+        ("distance", Json::Int(r.stats.distance as i64)),
+        ("instructions", Json::Int(r.stats.instructions as i64)),
+    ])
+}
+
+// Describes a finished headless match: its configuration, how it ended and the final
+// statistics of every robot, including the destroyed ones
+pub fn match_report(config: &MatchConfig, battle: &Battle) -> Json {
+    let mut robots: Vec<RobotSummary> = battle
+        .robots
+        .iter()
+        .map(|r| r.summary())
+        .chain(battle.destroyed.iter().cloned())
+        .collect();
+    robots.sort_by_key(|r| r.id);
+
+    let winner = match battle.winner() {
+        Some(team) => Json::object(vec![
+            ("team", Json::Int(team as i64)),
+            (
+                "robots",
+                Json::Array(battle.robots.iter().map(|r| Json::str(&r.name)).collect()),
+            ),
+        ]),
+        None => Json::Null,
+    };
+
+    Json::object(vec![
+        ("config", config_json(config)),
+        ("seed", Json::UInt(config.seed)),
+        ("ticks", Json::Int(battle.tick as i64)),
+        ("winner", winner),
+        (
+            "robots",
+            Json::Array(robots.iter().map(robot_json).collect()),
+        ),
+    ])
+}
+
+// Writes a document to a file, or to the standard output if the path is -
+pub fn write_json(json: &Json, path: &str) -> Result<(), String> {
+    let text = json.to_pretty_string();
+    if path == "-" {
+        print!("{}", text);
+        return Ok(());
+    }
+    match std::fs::write(path, text) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(format!("Couldn't write {}: {}", path, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(json: &Json) -> Vec<&str> {
+        match json {
+            Json::Object(fields) => fields.iter().map(|(k, _)| k.as_str()).collect(),
+            _ => vec![],
+        }
+    }
+
+    fn field<'a>(json: &'a Json, key: &str) -> &'a Json {
+        match json {
+            Json::Object(fields) => &fields.iter().find(|(k, _)| k == key).unwrap().1,
+            _ => panic!("{} is not in an object", key),
+        }
+    }
+
+    #[test]
+    fn reports_describe_the_whole_match() {
+        let config = MatchConfig::parse(
+            "seed 18446744073709551615
+robot Survivor
+  team 1
+robot Wreck
+  team 2
+",
+        )
+        .unwrap();
+        let wreck = Robot {
+            id: 1,
+            name: String::from("Wreck"),
+            team: 2,
+            health: 0,
+            ..Default::default()
+        };
+        let mut battle = Battle::new(
+            vec![Robot {
+                name: String::from("Survivor"),
+                ..Default::default()
+            }],
+            config.seed,
+        );
+        battle.destroyed.push(wreck.summary());
+        battle.tick = 12;
+
+        let report = match_report(&config, &battle);
+        assert_eq!(
+            keys(&report),
+            vec!["config", "seed", "ticks", "winner", "robots"]
+        );
+        assert_eq!(
+            keys(field(&report, "config")),
+            vec![
+                "field",
+                "max_ticks",
+                "shuffle_spawns",
+                "pickups",
+                "pickup_rate",
+                "robots"
+            ]
+        );
+        assert!(matches!(field(&report, "seed"), Json::UInt(u64::MAX)));
+        assert!(matches!(field(&report, "ticks"), Json::Int(12)));
+        assert_eq!(keys(field(&report, "winner")), vec!["team", "robots"]);
+
+        // Destroyed robots are reported along with the survivors
+        let robots = match field(&report, "robots") {
+            Json::Array(robots) => robots,
+            _ => panic!("robots is not an array"),
+        };
+        assert_eq!(robots.len(), 2);
+        assert_eq!(
+            keys(&robots[1]),
+            vec![
+                "name",
+                "team",
+                "alive",
+                "position",
+                "health",
+                "bullets",
+                "energy",
+                "shots_fired",
+                "shots_hit",
+                "distance",
+                "instructions"
+            ]
+        );
+        assert!(matches!(field(&robots[0], "alive"), Json::Bool(true)));
+        assert!(matches!(field(&robots[1], "alive"), Json::Bool(false)));
+    }
+}
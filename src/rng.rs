@@ -0,0 +1,48 @@
+// Deterministic pseudo random number generator (SplitMix64). Everything random in a
+// battle is drawn from generators seeded by the match, so battles can be replayed.
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    // Independent generator for a stream of the same seed. Stream 0 is used by the
+    // engine, the others by the robots.
+    pub fn stream(seed: u64, stream: u64) -> Rng {
+        let mut mixer = Rng::new(stream);
+        Rng::new(seed ^ mixer.next_u64())
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // Random number in [0, n)
+    pub fn range(&mut self, n: u32) -> u32 {
+        (self.next_u64() % n as u64) as u32
+    }
+
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = self.range(i as u32 + 1) as usize;
+            items.swap(i, j);
+        }
+    }
+}
+
+// FNV-1a hash, stable across platforms and compiler versions
+pub fn hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
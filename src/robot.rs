@@ -1,12 +1,35 @@
 use super::*;
 
-use crate::parser::Instruction::*;
-use crate::parser::Value::*;
+use crate::bytecode::*;
+use crate::component::*;
+use crate::disasm::*;
+use crate::parser::include::*;
 use crate::parser::*;
+use crate::pickup::*;
+use crate::radio::*;
+use crate::rng::*;
+
+use std::collections::VecDeque;
+use std::path::PathBuf;
+
+// Clock cycles every core receives each tick
+pub const CYCLES_PER_TICK: i32 = 4;
+
+// Ticks a shield stays up once activated
+pub const SHIELD_TICKS: i32 = 3;
+
+// Number of hits kept in the damage log of a battle. When the log is full, the oldest
+// hit is dropped.
+pub const DAMAGE_LOG_SIZE: usize = 32;
 
 pub struct Core {
     pub source: String,
-    pub instructions: Vec<Instruction>,
+    pub path: PathBuf, // File the source was read from, includes are relative to it
+    pub source_map: SourceMap, // Files of the program, once compiled
+    pub code: Vec<Op>,
+    pub lines: Vec<usize>, // Line of the program of each instruction
+    // Source shown for programs loaded as bytecode, with the line of each instruction
+    pub disassembly: (String, Vec<usize>),
     // Registers
     pub a: i16,
     pub b: i16,
@@ -17,6 +40,11 @@ pub struct Core {
     pub cx: i16,
     pub cy: i16,
 
+    pub msg: i16, // Messages waiting to be received
+
+    // Cycles left over from previous ticks
+    pub cycles: i32,
+
     // Flags
     pub e: bool,
     pub g: bool,
@@ -27,7 +55,11 @@ impl Default for Core {
     fn default() -> Core {
         Core {
             source: String::from(""),
-            instructions: vec![],
+            path: PathBuf::new(),
+            source_map: SourceMap::default(),
+            code: vec![],
+            lines: vec![],
+            disassembly: (String::new(), vec![]),
             a: 0,
             b: 0,
             c: 0,
@@ -37,6 +69,10 @@ impl Default for Core {
             cx: 0,
             cy: 0,
 
+            msg: 0,
+
+            cycles: 0,
+
             e: false,
             g: false,
             l: false,
@@ -44,13 +80,190 @@ impl Default for Core {
     }
 }
 
+// Limits on the programs a core accepts, so that untrusted programs can't hang the
+// engine or eat up its memory
+pub const MAX_SOURCE_SIZE: usize = 64 * 1024; // Bytes
+pub const MAX_INSTRUCTIONS: usize = 4096;
+pub const MAX_LABELS: usize = 1024;
+
 impl Core {
-    pub fn compile(&mut self) {
-        self.instructions = parse(&self.source);
+    pub fn compile(&mut self) -> Result<(), String> {
+        self.code.clear();
+        self.lines.clear();
+        self.source_map = SourceMap::default();
+        self.disassembly = (String::new(), vec![]);
+
+        // Checked before parsing, so huge sources are never lexed
+        if self.source.len() > MAX_SOURCE_SIZE {
+            return Err(format!(
+                "Program is too long: {} bytes, the maximum is {}",
+                self.source.len(),
+                MAX_SOURCE_SIZE
+            ));
+        }
+
+        let (instructions, labels, source_map) = parse_file(&self.path, &self.source)?;
+        if instructions.len() > MAX_INSTRUCTIONS {
+            return Err(format!(
+                "Program has too many instructions: {}, the maximum is {}",
+                instructions.len(),
+                MAX_INSTRUCTIONS
+            ));
+        }
+        if labels.len() > MAX_LABELS {
+            return Err(format!(
+                "Program has too many labels: {}, the maximum is {}",
+                labels.len(),
+                MAX_LABELS
+            ));
+        }
+
+        let bytecode = assemble(&instructions, &labels).map_err(|e| source_map.locate_error(e))?;
+        self.code = bytecode.code;
+        self.lines = bytecode.lines;
+        self.source_map = source_map;
+        Ok(())
     }
+
+    // Loads a program compiled by hbot build, instead of compiling the source
+    pub fn load_bytecode(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.code.clear();
+        self.lines.clear();
+        self.source_map = SourceMap::default();
+        self.disassembly = (String::new(), vec![]);
+
+        let bytecode = decode(bytes)?;
+        if bytecode.code.len() > MAX_INSTRUCTIONS {
+            return Err(format!(
+                "Program has too many instructions: {}, the maximum is {}",
+                bytecode.code.len(),
+                MAX_INSTRUCTIONS
+            ));
+        }
+        self.disassembly = disassemble(&bytecode.code);
+        self.code = bytecode.code;
+        self.lines = bytecode.lines;
+        Ok(())
+    }
+}
+
+// Program as read from a file, either source or bytecode compiled by hbot build
+pub enum ProgramFile {
+    Source(PathBuf, String),
+    Bytecode(Vec<u8>),
+}
+
+impl ProgramFile {
+    pub fn bytes(&self) -> &[u8] {
+        match self {
+            ProgramFile::Source(_, source) => source.as_bytes(),
+            ProgramFile::Bytecode(bytes) => bytes,
+        }
+    }
+}
+
+// Reads a program, refusing files too big to be compiled without loading them in
+// memory. Files with the .hbc extension are read as bytecode.
+pub fn read_program(path: &str) -> Result<ProgramFile, String> {
+    match fs::metadata(path) {
+        Ok(meta) if meta.len() > MAX_SOURCE_SIZE as u64 => {
+            return Err(format!(
+                "{} is too long: {} bytes, the maximum is {}",
+                path,
+                meta.len(),
+                MAX_SOURCE_SIZE
+            ))
+        }
+        Ok(_) => (),
+        Err(e) => return Err(format!("Couldn't read {}: {}", path, e)),
+    }
+    if path.ends_with(".hbc") {
+        return match fs::read(path) {
+            Ok(bytes) => Ok(ProgramFile::Bytecode(bytes)),
+            Err(e) => Err(format!("Couldn't read {}: {}", path, e)),
+        };
+    }
+    match fs::read_to_string(path) {
+        Ok(source) => Ok(ProgramFile::Source(PathBuf::from(path), source)),
+        Err(e) => Err(format!("Couldn't read {}: {}", path, e)),
+    }
+}
+
+// Stats of the weapon mounted on the turret
+#[derive(Clone)]
+pub struct Weapon {
+    pub reload_ticks: i32, // Ticks it takes to reload the magazine
+    pub damage: i32,
+    pub range: i32, // Maximum distance of a target, in tiles
+    pub magazine_size: i32,
+}
+
+impl Default for Weapon {
+    fn default() -> Weapon {
+        Weapon {
+            reload_ticks: 3,
+            damage: 10,
+            range: 10,
+            magazine_size: 1,
+        }
+    }
+}
+
+// Damage absorbed by the armor plating on each side of the robot. Robots have no
+// armor unless the match configuration gives them some.
+#[derive(Clone, Default)]
+pub struct Armor {
+    pub front: i32,
+    pub side: i32,
+    pub rear: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HitSide {
+    Front,
+    Side,
+    Rear,
+}
+
+// A hit a robot received during the battle
+#[derive(Debug, Clone)]
+pub struct DamageEvent {
+    pub tick: u32,
+    pub attacker: String,
+    pub target: String,
+    pub side: HitSide,
+    pub damage: i32, // Damage of the weapon
+    pub dealt: i32,  // Damage left after armor and shield
+    pub shielded: bool,
+}
+
+// What a robot did during the battle
+#[derive(Debug, Clone, Default)]
+pub struct RobotStats {
+    pub shots_fired: u32,
+    pub shots_hit: u32,
+    pub distance: u32, // Tiles moved
+    pub instructions: u32,
+}
+
+// What's kept of a robot once it's destroyed, for the statistics of the match
+#[derive(Debug, Clone)]
+pub struct RobotSummary {
+    pub id: usize,
+    pub name: String,
+    pub team: i32,
+    pub x: i32,
+    pub y: i32,
+    pub direction: (i32, i32),
+    pub health: i32,
+    pub bullets: i32,
+    pub magazine: i32,
+    pub energy: i32,
+    pub stats: RobotStats,
 }
 
 pub struct Robot {
+    pub id: usize, // Unique in the battle
     pub x: i32,
     pub y: i32,
     pub direction: (i32, i32),
@@ -59,18 +272,32 @@ pub struct Robot {
     pub health: i32,
     pub bullets: i32,
 
+    pub max_energy: i32,
+    pub energy: i32,
+    pub energy_recharge: i32, // Energy gained every tick
+
     pub team: i32,
     pub color: Color,
     pub name: String,
 
     pub core: Core,
-    pub gun_loaded: bool,
+    pub components: Vec<Box<dyn Component>>,
+    pub weapon: Weapon,
+    pub magazine: i32,     // Rounds loaded in the turret
+    pub reload_timer: i32, // Ticks left until the reload is complete, 0 if not reloading
+    pub armor: Armor,
+    pub shield: i32,          // Ticks left before the shield goes down
+    pub outbox: Vec<i16>,     // Messages sent during the current tick
+    pub radio_cursor: u64,    // Id of the next message to receive
+    pub rng: Rng,             // Random numbers of the rnd instruction
     pub aoi: Vec<(i32, i32)>, // Area of influence
+    pub stats: RobotStats,
 }
 
 impl Default for Robot {
     fn default() -> Robot {
         Robot {
+            id: 0,
             x: 0,
             y: 0,
             direction: (0, 1),
@@ -79,6 +306,10 @@ impl Default for Robot {
             health: 100,
             bullets: 100,
 
+            max_energy: 100,
+            energy: 100,
+            energy_recharge: 5,
+
             team: 1,
             color: Color::RED,
             name: String::from("Unnamed"),
@@ -86,54 +317,260 @@ impl Default for Robot {
             core: Core {
                 ..Default::default()
             },
-            gun_loaded: true,
+            components: default_loadout(),
+            weapon: Weapon::default(),
+            magazine: 1,
+            reload_timer: 0,
+            armor: Armor::default(),
+            shield: 0,
+            outbox: vec![],
+            radio_cursor: 0,
+            rng: Rng::new(0),
             aoi: vec![],
+            stats: RobotStats::default(),
         }
     }
 }
 
-enum Action {
+// What a core can see of a robot. Cores only look at the battle as it was at the
+// beginning of the tick, so the order they are stepped in doesn't matter.
+pub struct RobotView {
+    pub id: usize,
+    pub name: String,
+    pub x: i32,
+    pub y: i32,
+    pub direction: (i32, i32),
+    pub team: i32,
+}
+
+// State of the battle at the beginning of a tick
+pub struct Snapshot<'a> {
+    pub robots: Vec<RobotView>,
+    pub radio: &'a TeamRadio,
+    pub pickups: &'a Vec<Pickup>,
+    pub field: &'a Field,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
     None,
     Move(i32, i32),
-    Shoot(usize), // Index of target robot
+    Shoot(usize), // Id of target robot
 }
 
 impl Robot {
-    pub fn resolve_value(&self, val: &Value) -> i16 {
-        match val {
-            Immediate(imm) => *imm,
-            Identifier(id) => 0, // TODO: this shouldn't happen in the future
-            Register(reg) => match reg.as_str() {
-                "a" => self.core.a,
-                "b" => self.core.b,
-                "c" => self.core.c,
-                "ip" => self.core.ip,
-                _ => {
-                    println!("Invalid register");
-                    0
+    pub fn summary(&self) -> RobotSummary {
+        RobotSummary {
+            id: self.id,
+            name: self.name.clone(),
+            team: self.team,
+            x: self.x,
+            y: self.y,
+            direction: self.direction,
+            health: self.health,
+            bullets: self.bullets,
+            magazine: self.magazine,
+            energy: self.energy,
+            stats: self.stats.clone(),
+        }
+    }
+
+    pub fn view(&self) -> RobotView {
+        RobotView {
+            id: self.id,
+            name: self.name.clone(),
+            x: self.x,
+            y: self.y,
+            direction: self.direction,
+            team: self.team,
+        }
+    }
+
+    pub fn has_instruction(&self, opcode: &str) -> bool {
+        CORE_INSTRUCTIONS.contains(&opcode)
+            || self
+                .components
+                .iter()
+                .any(|c| c.instructions().contains(&opcode))
+    }
+
+    pub fn has_register(&self, reg: &str) -> bool {
+        CORE_REGISTERS.contains(&reg)
+            || self.components.iter().any(|c| c.registers().contains(&reg))
+    }
+
+    // Compiles the robot's program, rejecting it if it uses instructions or registers
+    // provided by components the robot doesn't have
+    pub fn compile(&mut self) -> Result<(), String> {
+        if let Err(e) = self.core.compile() {
+            return Err(format!("{}: {}", self.name, e));
+        }
+        self.check_components()
+    }
+
+    // Loads a program read from a file, compiling it if it's source
+    pub fn load_program(&mut self, program: ProgramFile) -> Result<(), String> {
+        match program {
+            ProgramFile::Source(path, source) => {
+                self.core.path = path;
+                self.core.source = source;
+                self.compile()
+            }
+            ProgramFile::Bytecode(bytes) => {
+                self.core.path = PathBuf::new();
+                self.core.source = String::new();
+                if let Err(e) = self.core.load_bytecode(&bytes) {
+                    return Err(format!("{}: {}", self.name, e));
                 }
-            },
+                self.check_components()
+            }
         }
     }
 
-    pub fn store_value(&mut self, dest: &Value, src: i16) {
-        if let Register(reg) = dest {
-            match reg.as_str() {
-                "a" => self.core.a = src,
-                "b" => self.core.b = src,
-                "c" => self.core.c = src,
-                "ip" => self.core.ip = src,
+    fn check_components(&mut self) -> Result<(), String> {
+        let mut error = None;
+        for (op, line) in self.core.code.iter().zip(self.core.lines.iter()) {
+            if !self.has_instruction(op.opcode.name()) {
+                error = Some(format!(
+                    "{}: {} has no component providing instruction {}",
+                    self.core.source_map.describe(*line),
+                    self.name,
+                    op.opcode.name()
+                ));
+                break;
+            }
 
-                "dir" => self.core.dir = src,
-                "cx" => self.core.cx = src,
-                "cy" => self.core.cy = src,
-                _ => {
-                    println!("Invalid register");
+            for reg in op.registers() {
+                if !self.has_register(reg.name()) {
+                    error = Some(format!(
+                        "{}: {} has no component providing register {}",
+                        self.core.source_map.describe(*line),
+                        self.name,
+                        reg.name()
+                    ));
                 }
-            };
+            }
+            if error.is_some() {
+                break;
+            }
+        }
+
+        if let Some(error) = error {
+            self.core.code.clear();
+            self.core.lines.clear();
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    pub fn register(&self, reg: Register) -> i16 {
+        match reg {
+            Register::A => self.core.a,
+            Register::B => self.core.b,
+            Register::C => self.core.c,
+            Register::Ip => self.core.ip,
+
+            Register::Dir => self.core.dir,
+            Register::Cx => self.core.cx,
+            Register::Cy => self.core.cy,
+
+            Register::Msg => self.core.msg,
+            Register::Energy => self.energy as i16,
+            Register::Loaded => self.loaded() as i16,
+            Register::Shield => self.shield as i16,
+        }
+    }
+
+    pub fn set_register(&mut self, reg: Register, value: i16) {
+        match reg {
+            Register::A => self.core.a = value,
+            Register::B => self.core.b = value,
+            Register::C => self.core.c = value,
+            Register::Ip => self.core.ip = value,
+
+            Register::Dir => self.core.dir = value,
+            Register::Cx => self.core.cx = value,
+            Register::Cy => self.core.cy = value,
+
+            Register::Energy | Register::Loaded | Register::Shield | Register::Msg => {
+                println!("Register {} is read-only", reg.name())
+            }
+        }
+    }
+
+    pub fn read(&self, operand: Operand, world: &Snapshot) -> i16 {
+        match operand {
+            Operand::None => 0,
+            Operand::Register(reg) => self.register(reg),
+            Operand::Immediate(imm) => imm,
+            Operand::Constant(Constant::Width) => world.field.width as i16,
+            Operand::Constant(Constant::Height) => world.field.height as i16,
+            Operand::Constant(Constant::Id) => self.id as i16,
+        }
+    }
+
+    pub fn write(&mut self, operand: Operand, value: i16) {
+        match operand {
+            Operand::Register(reg) => self.set_register(reg, value),
+            _ => println!("Dest is not a register"),
+        }
+    }
+
+    // Rounds ready to be fired
+    pub fn loaded(&self) -> i32 {
+        if self.reload_timer > 0 {
+            0
+        } else {
+            self.magazine
+        }
+    }
+
+    // Starts reloading the turret, if there is anything to reload
+    pub fn reload(&mut self) {
+        if self.reload_timer > 0 || self.magazine >= self.weapon.magazine_size || self.bullets <= 0
+        {
             return;
         }
-        dbg!("Dest is not a register");
+
+        self.reload_timer = self.weapon.reload_ticks;
+        if self.reload_timer <= 0 {
+            self.finish_reload();
+        }
+    }
+
+    // Moves bullets into the magazine
+    fn finish_reload(&mut self) {
+        let rounds = std::cmp::min(self.weapon.magazine_size - self.magazine, self.bullets);
+        self.magazine += rounds;
+        self.bullets -= rounds;
+        self.reload_timer = 0;
+    }
+
+    // Side of the robot hit by a shot travelling in the given direction
+    pub fn hit_side(&self, shot_direction: (i32, i32)) -> HitSide {
+        if self.direction == (-shot_direction.0, -shot_direction.1) {
+            HitSide::Front
+        } else if self.direction == shot_direction {
+            HitSide::Rear
+        } else {
+            HitSide::Side
+        }
+    }
+
+    // Runs a hit through shield and armor, returning the damage actually dealt
+    pub fn take_hit(&mut self, damage: i32, side: HitSide) -> i32 {
+        if self.shield > 0 {
+            return 0;
+        }
+
+        let armor = match side {
+            HitSide::Front => self.armor.front,
+            HitSide::Side => self.armor.side,
+            HitSide::Rear => self.armor.rear,
+        };
+        let dealt = std::cmp::max(0, damage - armor);
+        self.health -= dealt;
+        dealt
     }
 
     pub fn update_flags(&mut self, val: i16) {
@@ -166,223 +603,1462 @@ impl Robot {
             Color::WHITE,
         );
 
-        let line: usize = match self.core.instructions[self.core.ip as usize] {
-            Nop(line) => line,
-            Mov(line, _, _) => line,
-            Add(line, _, _) => line,
-            Sub(line, _, _) => line,
-            Cmp(line, _, _) => line,
-            And(line, _, _) => line,
-            Or(line, _, _) => line,
-            Xor(line, _, _) => line,
-            Not(line, _) => line,
-
-            Jmp(line, _) => line,
-            Je(line, _) => line,
-            Jg(line, _) => line,
-            Jl(line, _) => line,
-
-            Fwd(line) => line,
-            Rol(line) => line,
-            Ror(line) => line,
-
-            Sht(line) => line,
-            Rld(line) => line,
-
-            Rad(line) => line,
-            Chk(line) => line,
-            _ => 0,
+        // Programs loaded as bytecode have no source, show their disassembly instead.
+        // Otherwise show the file the instruction comes from.
+        let ip = self.core.ip as usize;
+        let (source, line) = if self.core.source.is_empty() {
+            let (source, lines) = &self.core.disassembly;
+            (source, lines.get(ip).copied().unwrap_or(0))
+        } else {
+            let line = self.core.lines.get(ip).copied().unwrap_or(0);
+            let (file, line) = self.core.source_map.locate(line);
+            let source = match self.core.source_map.files.get(file) {
+                Some(file) => &file.source,
+                None => &self.core.source,
+            };
+            (source, line)
         };
 
         let ip_y = 90 + line as i32 * 60;
         d.draw_rectangle(50, ip_y, 800, 40, Color::GRAY);
-        d.draw_text(self.core.source.as_str(), 50, 90, 40, Color::WHITE);
+        d.draw_text(source.as_str(), 50, 90, 40, Color::WHITE);
     }
 }
 
-pub fn step_robot(i: usize, robots: &mut Vec<Robot>, field: &Field) -> Action {
+// Runs the core of a robot for one tick. Instructions are executed until the cycle
+// budget runs out or a motor or turret instruction is executed. Cycles that aren't
+// enough to execute the next instruction are kept for the next tick. Instructions
+// that need more energy than is available wait until the robot has recharged.
+pub fn step_robot(robot: &mut Robot, world: &Snapshot) -> Action {
     let mut action = Action::None;
-    if robots[i].core.instructions.len() == 0 {
+    robot.energy = std::cmp::min(robot.max_energy, robot.energy + robot.energy_recharge);
+    if robot.core.code.is_empty() {
         return action;
     }
-    if robots[i].core.ip as usize >= robots[i].core.instructions.len() {
-        robots[i].core.ip = 0;
+
+    robot.core.msg = world
+        .radio
+        .pending(robot.team, robot.id, robot.radio_cursor)
+        .len() as i16;
+
+    if robot.shield > 0 {
+        robot.shield -= 1;
+    }
+
+    if robot.reload_timer > 0 {
+        robot.reload_timer -= 1;
+        if robot.reload_timer == 0 {
+            robot.finish_reload();
+        }
+    }
+
+    robot.core.cycles += CYCLES_PER_TICK;
+    loop {
+        if robot.core.ip as usize >= robot.core.code.len() {
+            robot.core.ip = 0;
+        }
+        let opcode = robot.core.code[robot.core.ip as usize].opcode;
+        let cost = opcode.cost();
+        let physical = opcode.is_physical();
+        let energy = opcode.energy();
+        if cost > robot.core.cycles {
+            break;
+        }
+        if energy > robot.energy {
+            // Wait for the batteries to recharge, without hoarding cycles
+            robot.core.cycles = cost;
+            break;
+        }
+
+        robot.core.cycles -= cost;
+        // Shots only use energy when they're fired at a target
+        if opcode != Opcode::Sht {
+            robot.energy -= energy;
+        }
+        action = execute_instruction(robot, world);
+        robot.stats.instructions += 1;
+        if physical {
+            // Physical actions end the tick
+            robot.core.cycles = 0;
+            break;
+        }
     }
-    let inst = robots[i].core.instructions[robots[i].core.ip as usize].clone();
-    match inst {
+    return action;
+}
+
+// Executes the instruction pointed to by ip
+fn execute_instruction(robot: &mut Robot, world: &Snapshot) -> Action {
+    let mut action = Action::None;
+    let op = robot.core.code[robot.core.ip as usize];
+    match op.opcode {
         // General purpouse
-        Nop(_) => {}
+        Opcode::Nop => {}
+
+        Opcode::Mov => {
+            let src = robot.read(op.src, world);
+            robot.write(op.dest, src);
+        }
 
-        Mov(_, dest, src) => {
-            let src = robots[i].resolve_value(&src);
-            robots[i].store_value(&dest, src);
+        Opcode::Add => {
+            let res = robot
+                .read(op.dest, world)
+                .wrapping_add(robot.read(op.src, world));
+            robot.write(op.dest, res);
+            robot.update_flags(res);
         }
 
-        Add(_, dest, src) => {
-            let res = robots[i].resolve_value(&dest) + robots[i].resolve_value(&src);
-            robots[i].store_value(&dest, res);
-            robots[i].update_flags(res);
+        Opcode::Sub => {
+            let res = robot
+                .read(op.dest, world)
+                .wrapping_sub(robot.read(op.src, world));
+            robot.write(op.dest, res);
+            robot.update_flags(res);
         }
 
-        Sub(_, dest, src) => {
-            let res = robots[i].resolve_value(&dest) - robots[i].resolve_value(&src);
-            robots[i].store_value(&dest, res);
-            robots[i].update_flags(res);
+        Opcode::Cmp => {
+            let res = robot
+                .read(op.dest, world)
+                .wrapping_sub(robot.read(op.src, world));
+            robot.update_flags(res);
         }
 
-        Cmp(_, dest, src) => {
-            let res = robots[i].resolve_value(&dest) - robots[i].resolve_value(&src);
-            robots[i].update_flags(res);
+        Opcode::And => {
+            let res = robot.read(op.dest, world) & robot.read(op.src, world);
+            robot.write(op.dest, res);
+            robot.update_flags(res);
         }
 
-        And(_, dest, src) => {
-            let res = robots[i].resolve_value(&dest) & robots[i].resolve_value(&src);
-            robots[i].store_value(&dest, res);
-            robots[i].update_flags(res);
+        Opcode::Or => {
+            let res = robot.read(op.dest, world) | robot.read(op.src, world);
+            robot.write(op.dest, res);
+            robot.update_flags(res);
         }
 
-        Or(_, dest, src) => {
-            let res = robots[i].resolve_value(&dest) | robots[i].resolve_value(&src);
-            robots[i].store_value(&dest, res);
-            robots[i].update_flags(res);
+        Opcode::Xor => {
+            let res = robot.read(op.dest, world) ^ robot.read(op.src, world);
+            robot.write(op.dest, res);
+            robot.update_flags(res);
         }
 
-        Xor(_, dest, src) => {
-            let res = robots[i].resolve_value(&dest) ^ robots[i].resolve_value(&src);
-            robots[i].store_value(&dest, res);
-            robots[i].update_flags(res);
+        Opcode::Not => {
+            let res = !robot.read(op.dest, world);
+            robot.write(op.dest, res);
+            robot.update_flags(res);
         }
 
-        Not(_, dest) => {
-            let res = !robots[i].resolve_value(&dest);
-            robots[i].store_value(&dest, res);
-            robots[i].update_flags(res);
+        Opcode::Rnd => {
+            let res = (robot.rng.next_u64() & 0x7fff) as i16;
+            robot.write(op.dest, res);
         }
 
         // Control flow
-        Jmp(_, dest) => {
-            let dest = robots[i].resolve_value(&dest);
-            robots[i].core.ip = dest - 1;
+        Opcode::Jmp => {
+            let dest = robot.read(op.dest, world);
+            robot.core.ip = dest.wrapping_sub(1);
         }
 
-        Je(_, dest) => {
-            if robots[i].core.e {
-                let dest = robots[i].resolve_value(&dest);
-                robots[i].core.ip = dest - 1;
+        Opcode::Je => {
+            if robot.core.e {
+                let dest = robot.read(op.dest, world);
+                robot.core.ip = dest.wrapping_sub(1);
             }
         }
 
-        Jg(_, dest) => {
-            if robots[i].core.g {
-                let dest = robots[i].resolve_value(&dest);
-                robots[i].core.ip = dest - 1;
+        Opcode::Jg => {
+            if robot.core.g {
+                let dest = robot.read(op.dest, world);
+                robot.core.ip = dest.wrapping_sub(1);
             }
         }
 
-        Jl(_, dest) => {
-            if robots[i].core.l {
-                let dest = robots[i].resolve_value(&dest);
-                robots[i].core.ip = dest - 1;
+        Opcode::Jl => {
+            if robot.core.l {
+                let dest = robot.read(op.dest, world);
+                robot.core.ip = dest.wrapping_sub(1);
             }
         }
 
         // Motor
-        Fwd(_) => {
+        Opcode::Fwd => {
             action = Action::Move(
                 std::cmp::min(
-                    field.width - 1,
-                    std::cmp::max(0, robots[i].x + robots[i].direction.0),
+                    world.field.width - 1,
+                    std::cmp::max(0, robot.x + robot.direction.0),
                 ),
                 std::cmp::min(
-                    field.height - 1,
-                    std::cmp::max(0, robots[i].y + robots[i].direction.1),
+                    world.field.height - 1,
+                    std::cmp::max(0, robot.y + robot.direction.1),
                 ),
             );
         }
 
-        Rol(_) => match robots[i].direction {
-            (0, 1) => robots[i].direction = (1, 0),
-            (1, 0) => robots[i].direction = (0, -1),
-            (0, -1) => robots[i].direction = (-1, 0),
-            (-1, 0) => robots[i].direction = (0, 1),
+        Opcode::Rol => match robot.direction {
+            (0, 1) => robot.direction = (1, 0),
+            (1, 0) => robot.direction = (0, -1),
+            (0, -1) => robot.direction = (-1, 0),
+            (-1, 0) => robot.direction = (0, 1),
             (_, _) => println!("Robot has impossible direction"),
         },
 
-        Ror(_) => match robots[i].direction {
-            (0, 1) => robots[i].direction = (-1, 0),
-            (-1, 0) => robots[i].direction = (0, -1),
-            (0, -1) => robots[i].direction = (1, 0),
-            (1, 0) => robots[i].direction = (0, 1),
+        Opcode::Ror => match robot.direction {
+            (0, 1) => robot.direction = (-1, 0),
+            (-1, 0) => robot.direction = (0, -1),
+            (0, -1) => robot.direction = (1, 0),
+            (1, 0) => robot.direction = (0, 1),
             (_, _) => println!("Robot has impossible direction"),
         },
 
-        Sht(_) => {
-            if robots[i].bullets > 0 && robots[i].gun_loaded {
-                robots[i].bullets -= 1;
-                robots[i].gun_loaded = false;
+        Opcode::Sht => {
+            if robot.loaded() > 0 {
+                robot.magazine -= 1;
+                robot.stats.shots_fired += 1;
 
-                let x = robots[i].x * robots[i].direction.0;
-                let y = robots[i].y * robots[i].direction.1;
+                let x = robot.x * robot.direction.0;
+                let y = robot.y * robot.direction.1;
 
-                let mut min_dist = i32::MAX;
-                let mut target: Option<usize> = None;
-                for j in 0..robots.len() {
-                    if j == i {
+                let mut target: Option<(i32, &RobotView)> = None;
+                for r in world.robots.iter() {
+                    if r.id == robot.id {
                         continue;
                     }
-                    let xe = robots[j].x * robots[i].direction.0;
-                    let ye = robots[j].y * robots[i].direction.1;
+                    let xe = r.x * robot.direction.0;
+                    let ye = r.y * robot.direction.1;
                     let dist = (x.abs() - xe.abs()).abs() + (y.abs() - ye.abs()).abs();
-                    if robots[i].direction.0 == 0 {
-                        if robots[i].x == robots[j].x && y < ye && dist < min_dist {
-                            min_dist = dist;
-                            target = Some(j);
-                        }
+                    if dist > robot.weapon.range {
+                        continue;
+                    }
+                    let in_line = if robot.direction.0 == 0 {
+                        robot.x == r.x && y < ye
                     } else {
-                        if x < xe && robots[i].y == robots[j].y && dist < min_dist {
-                            min_dist = dist;
-                            target = Some(j);
-                        }
+                        x < xe && robot.y == r.y
+                    };
+                    if in_line && is_closer(dist, r, &target) {
+                        target = Some((dist, r));
                     }
                 }
-                if let Some(t) = target {
-                    action = Action::Shoot(t);
+                if let Some((_, t)) = target {
+                    robot.energy -= Opcode::Sht.energy();
+                    action = Action::Shoot(t.id);
+                }
+            }
+        }
+
+        Opcode::Rld => {
+            robot.reload();
+        }
+
+        // Sensors
+        Opcode::Rad => {
+            if let Some(enemy) = closest_enemy(robot, world) {
+                let v = (enemy.x - robot.x, enemy.y - robot.y);
+                let d = robot.direction;
+
+                // Distance of the enemy on the left of the robot
+                let left = v.0 * d.1 - v.1 * d.0;
+                robot.core.dir = if left > 0 {
+                    -1
+                } else if left < 0 {
+                    1
+                } else if v.0 * d.0 + v.1 * d.1 > 0 {
+                    0
+                } else {
+                    // The enemy is right behind
+                    1
+                };
+            }
+        }
+
+        Opcode::Chk => {
+            robot.core.cx = -1;
+            robot.core.cy = -1;
+
+            let mut closest: Option<(i32, &RobotView)> = None;
+            for r in world.robots.iter() {
+                if r.team == robot.team {
+                    continue;
+                }
+                let offset = (r.x - robot.x, r.y - robot.y);
+                let dist = offset.0.abs() + offset.1.abs();
+                if robot.aoi.contains(&offset) && is_closer(dist, r, &closest) {
+                    closest = Some((dist, r));
+                }
+            }
+            if let Some((_, r)) = closest {
+                robot.core.cx = r.x as i16;
+                robot.core.cy = r.y as i16;
+            }
+        }
+
+        Opcode::Fnd => {
+            let kind = robot.read(op.dest, world);
+            robot.core.cx = -1;
+            robot.core.cy = -1;
+
+            let mut min_dist = i32::MAX;
+            for p in world.pickups.iter() {
+                if kind != 0 && kind != p.kind.id() {
+                    continue;
+                }
+                let dist = (p.x - robot.x).abs() + (p.y - robot.y).abs();
+                if dist < min_dist {
+                    min_dist = dist;
+                    robot.core.cx = p.x as i16;
+                    robot.core.cy = p.y as i16;
                 }
             }
         }
 
-        Rld(_) => {
-            robots[i].gun_loaded = true;
+        // Shield
+        Opcode::Shd => {
+            robot.shield = SHIELD_TICKS;
+        }
+
+        // Radio
+        Opcode::Snd => {
+            let src = robot.read(op.dest, world);
+            robot.outbox.push(src);
+        }
+
+        Opcode::Rcv => {
+            let next = world
+                .radio
+                .pending(robot.team, robot.id, robot.radio_cursor)
+                .first()
+                .map(|m| (m.id, m.value));
+            if let Some((id, value)) = next {
+                robot.write(op.dest, value);
+                robot.radio_cursor = id + 1;
+                robot.core.msg -= 1;
+            }
         }
 
         _ => {
             println!("Unhandled instruction");
         }
     };
-    robots[i].core.ip += 1;
+    robot.core.ip = robot.core.ip.wrapping_add(1);
     return action;
 }
 
-pub fn step_game(robots: &mut Vec<Robot>, field: &Field) {
+// Ties between robots at the same distance are broken by position and name, so the
+// result doesn't depend on the order of the robots
+fn is_closer(dist: i32, r: &RobotView, best: &Option<(i32, &RobotView)>) -> bool {
+    match best {
+        None => true,
+        Some((min_dist, b)) => (dist, r.y, r.x, &r.name) < (*min_dist, b.y, b.x, &b.name),
+    }
+}
+
+// Closest robot of another team, if any
+fn closest_enemy<'a>(robot: &Robot, world: &'a Snapshot) -> Option<&'a RobotView> {
+    let mut closest: Option<(i32, &RobotView)> = None;
+    for r in world.robots.iter() {
+        if r.team == robot.team {
+            continue;
+        }
+        let dist = (r.x - robot.x).abs() + (r.y - robot.y).abs();
+        if is_closer(dist, r, &closest) {
+            closest = Some((dist, r));
+        }
+    }
+    closest.map(|(_, r)| r)
+}
+
+// State of a battle that lasts across ticks
+pub struct Battle {
+    pub robots: Vec<Robot>,
+    pub tick: u32,
+    pub events: VecDeque<DamageEvent>, // Most recent hits, oldest first
+    pub destroyed: Vec<RobotSummary>,
+    pub radio: TeamRadio,
+    pub pickups: Vec<Pickup>,
+    pub pickup_rate: u32, // Ticks between random crates, 0 to disable them
+    pub rng: Rng,
+    seed: u64,
+    next_id: usize,
+}
+
+impl Battle {
+    pub fn new(robots: Vec<Robot>, seed: u64) -> Battle {
+        let mut battle = Battle {
+            robots: vec![],
+            tick: 0,
+            events: VecDeque::new(),
+            destroyed: vec![],
+            radio: TeamRadio::new(),
+            pickups: vec![],
+            pickup_rate: 0,
+            rng: Rng::stream(seed, 0),
+            seed,
+            next_id: 0,
+        };
+        for robot in robots {
+            battle.add(robot);
+        }
+        battle
+    }
+
+    // Adds a robot to the battle, giving it a unique id and its own random numbers.
+    // The random numbers depend on the robot's name, team and starting position
+    // rather than on the order of the robots.
+    pub fn add(&mut self, mut robot: Robot) {
+        robot.id = self.next_id;
+        let key = format!("{} {} {} {}", robot.name, robot.team, robot.x, robot.y);
+        robot.rng = Rng::stream(self.seed, hash(key.as_bytes()));
+        self.next_id += 1;
+        self.robots.push(robot);
+    }
+
+    // Randomly swaps the starting positions and directions of the robots
+    pub fn shuffle_spawns(&mut self) {
+        let order = resolution_order(&self.robots);
+        let mut spawns: Vec<(i32, i32, (i32, i32))> = self
+            .robots
+            .iter()
+            .map(|r| (r.x, r.y, r.direction))
+            .collect();
+        spawns.sort();
+        self.rng.shuffle(&mut spawns);
+        for (&i, spawn) in order.iter().zip(spawns) {
+            self.robots[i].x = spawn.0;
+            self.robots[i].y = spawn.1;
+            self.robots[i].direction = spawn.2;
+        }
+    }
+
+    // Team of the last robots standing, once all the others have been destroyed
+    pub fn winner(&self) -> Option<i32> {
+        let team = self.robots.first()?.team;
+        if self.robots.iter().all(|r| r.team == team) {
+            Some(team)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_over(&self) -> bool {
+        self.robots.is_empty() || self.winner().is_some()
+    }
+
+    // Drops a random crate on a free tile
+    fn spawn_pickup(&mut self, field: &Field) {
+        let mut free = vec![];
+        for y in 0..field.height {
+            for x in 0..field.width {
+                let robot = self.robots.iter().any(|r| r.x == x && r.y == y);
+                let pickup = self.pickups.iter().any(|p| p.x == x && p.y == y);
+                if !robot && !pickup {
+                    free.push((x, y));
+                }
+            }
+        }
+        if free.is_empty() {
+            return;
+        }
+
+        let (x, y) = free[self.rng.range(free.len() as u32) as usize];
+        let kind = match self.rng.range(3) {
+            0 => PickupKind::Ammo,
+            1 => PickupKind::Health,
+            _ => PickupKind::Energy,
+        };
+        self.pickups.push(Pickup { x, y, kind });
+    }
+}
+
+// Order in which the effects of the robots are resolved, independent of their
+// position in the battle
+fn resolution_order(robots: &[Robot]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..robots.len()).collect();
+    order.sort_by(|&a, &b| {
+        (&robots[a].name, robots[a].team, robots[a].x, robots[a].y).cmp(&(
+            &robots[b].name,
+            robots[b].team,
+            robots[b].x,
+            robots[b].y,
+        ))
+    });
+    order
+}
+
+// Advances the battle by one tick. In the first phase, all cores run against a
+// snapshot of the battle taken at the beginning of the tick. In the second phase, the
+// actions of all robots are resolved at the same time.
+pub fn step_game(battle: &mut Battle, field: &Field) {
+    let snapshot = Snapshot {
+        robots: battle.robots.iter().map(|r| r.view()).collect(),
+        radio: &battle.radio,
+        pickups: &battle.pickups,
+        field,
+    };
+
     let mut actions = vec![];
-    for i in 0..robots.len() {
-        actions.push(step_robot(i, robots, field));
+    for robot in battle.robots.iter_mut() {
+        actions.push(step_robot(robot, &snapshot));
+    }
+
+    let robots = &mut battle.robots;
+    let order = resolution_order(robots);
+
+    // Deliver messages, they can be received starting from the next tick
+    for &i in order.iter() {
+        let robot = &mut robots[i];
+        for value in robot.outbox.drain(..) {
+            battle
+                .radio
+                .send(robot.team, robot.id, &robot.name, value, battle.tick);
+        }
     }
 
     // Execute all moves
     for i in 0..robots.len() {
         if let Action::Move(x, y) = actions[i] {
+            robots[i].stats.distance += ((x - robots[i].x).abs() + (y - robots[i].y).abs()) as u32;
             robots[i].x = x;
             robots[i].y = y;
         }
     }
 
+    // Collect crates. Crates contended by more than one robot stay where they are
+    battle.pickups.retain(|p| {
+        let on_tile: Vec<usize> = (0..robots.len())
+            .filter(|&i| robots[i].x == p.x && robots[i].y == p.y)
+            .collect();
+        if on_tile.len() == 1 {
+            p.apply(&mut robots[on_tile[0]]);
+            return false;
+        }
+        true
+    });
+
     // Execute all shots
-    for i in 0..robots.len() {
-        if let Action::Shoot(target) = actions[i] {
-            robots[target].health -= 10;
+    for &i in order.iter() {
+        if let Action::Shoot(id) = actions[i] {
+            let target = match robots.iter().position(|r| r.id == id) {
+                Some(target) => target,
+                None => continue,
+            };
+            let damage = robots[i].weapon.damage;
+            let side = robots[target].hit_side(robots[i].direction);
+            let shielded = robots[target].shield > 0;
+            let dealt = robots[target].take_hit(damage, side);
+            robots[i].stats.shots_hit += 1;
+            if battle.events.len() >= DAMAGE_LOG_SIZE {
+                battle.events.pop_front();
+            }
+            battle.events.push_back(DamageEvent {
+                tick: battle.tick,
+                attacker: robots[i].name.clone(),
+                target: robots[target].name.clone(),
+                side,
+                damage,
+                dealt,
+                shielded,
+            });
+        }
+    }
+
+    // Only a summary of destroyed robots is kept, for the statistics of the match
+    let mut i = 0;
+    while i < robots.len() {
+        if robots[i].health <= 0 {
+            battle.destroyed.push(robots.remove(i).summary());
+        } else {
+            i += 1;
+        }
+    }
+
+    if battle.pickup_rate > 0
+        && (battle.tick + 1).is_multiple_of(battle.pickup_rate)
+        && battle.pickups.len() < MAX_RANDOM_PICKUPS
+    {
+        battle.spawn_pickup(field);
+    }
+    battle.tick += 1;
+}
+
+// Runs the battle without drawing it, until it's over or it reaches max_ticks.
+// Returns the winning team, or None for a draw.
+pub fn run_battle(battle: &mut Battle, field: &Field, max_ticks: u32) -> Option<i32> {
+    while !battle.is_over() && battle.tick < max_ticks {
+        step_game(battle, field);
+    }
+    battle.winner()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIELD: Field = Field {
+        x: 0,
+        y: 0,
+        width: 10,
+        height: 10,
+    };
+
+    fn robot(name: &str, source: &str, x: i32, y: i32, direction: (i32, i32), team: i32) -> Robot {
+        let mut robot = Robot {
+            name: String::from(name),
+            x,
+            y,
+            direction,
+            team,
+            aoi: vec![(0, 1), (0, 2), (1, 0), (-1, 0)],
+            core: Core {
+                source: String::from(source),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        robot.compile().unwrap();
+        robot
+    }
+
+    // Everything about a robot that can be affected by a tick
+    fn state(r: &Robot) -> String {
+        format!(
+            "{} {} {} {:?} {} {} {} {} {} {} {} {} {} {}",
+            r.name,
+            r.x,
+            r.y,
+            r.direction,
+            r.health,
+            r.energy,
+            r.magazine,
+            r.shield,
+            r.core.a,
+            r.core.b,
+            r.core.c,
+            r.core.ip,
+            r.core.cx,
+            r.core.cy,
+        )
+    }
+
+    // Runs the battle and describes its outcome independently of the order of the robots
+    fn run(robots: Vec<Robot>, ticks: u32) -> Vec<String> {
+        let mut battle = Battle::new(robots, 7);
+        battle.pickups.push(Pickup {
+            x: 4,
+            y: 4,
+            kind: PickupKind::Health,
+        });
+        battle.pickup_rate = 5;
+        for _ in 0..ticks {
+            step_game(&mut battle, &FIELD);
+        }
+
+        let mut outcome: Vec<String> = battle.robots.iter().map(state).collect();
+        outcome.sort();
+        for e in battle.events.iter() {
+            outcome.push(format!(
+                "{} {} {} {} {}",
+                e.tick, e.attacker, e.target, e.dealt, e.shielded
+            ));
+        }
+        let mut pickups: Vec<String> = battle
+            .pickups
+            .iter()
+            .map(|p| format!("{} {} {}", p.x, p.y, p.kind.id()))
+            .collect();
+        pickups.sort();
+        outcome.extend(pickups);
+        outcome
+    }
+
+    fn robots() -> Vec<Robot> {
+        vec![
+            robot("gunner", "sht\nrld\nrnd a\nrol\n", 0, 0, (1, 0), 1),
+            robot("target", "fwd\nsnd a\nadd a, 1\nror\n", 3, 0, (0, 1), 2),
+            robot("twin", "rad\nchk\nfwd\nrcv b\nsht\n", 3, 0, (-1, 0), 2),
+            robot(
+                "walker",
+                "rnd c\nfwd\nfwd\nror\nshd\nsht\n",
+                4,
+                2,
+                (0, 1),
+                1,
+            ),
+            robot("grabber", "fnd 2\nrol\nfwd\nsnd 1\n", 4, 6, (0, -1), 2),
+        ]
+    }
+
+    #[test]
+    fn reversing_robots_does_not_change_outcome() {
+        let mut reversed = robots();
+        reversed.reverse();
+        assert_eq!(run(robots(), 40), run(reversed, 40));
+    }
+
+    #[test]
+    fn rotating_robots_does_not_change_outcome() {
+        for k in 1..robots().len() {
+            let mut rotated = robots();
+            rotated.rotate_left(k);
+            assert_eq!(run(robots(), 40), run(rotated, 40));
+        }
+    }
+
+    #[test]
+    fn shots_are_resolved_simultaneously() {
+        // Both robots shoot each other on the same tick, no matter who comes first
+        for swap in [false, true] {
+            let mut robots = vec![
+                robot("left", "sht\n", 0, 0, (1, 0), 1),
+                robot("right", "sht\n", 2, 0, (-1, 0), 2),
+            ];
+            if swap {
+                robots.reverse();
+            }
+            let mut battle = Battle::new(robots, 0);
+            step_game(&mut battle, &FIELD);
+            assert_eq!(battle.events.len(), 2);
+            assert_eq!(battle.events[0].attacker, "left");
+            assert_eq!(battle.events[1].attacker, "right");
+        }
+    }
+
+    #[test]
+    fn damage_log_keeps_the_last_hits() {
+        let mut gunner = robot("gunner", "sht\nrld", 0, 0, (1, 0), 1);
+        gunner.weapon.damage = 1;
+        let mut target = robot("target", "", 2, 0, (1, 0), 2);
+        target.health = 100000;
+        let mut battle = Battle::new(vec![gunner, target], 0);
+        run_battle(&mut battle, &FIELD, 2000);
+
+        let hits = battle.robots[0].stats.shots_hit as usize;
+        assert!(hits > DAMAGE_LOG_SIZE, "{} hits", hits);
+        assert_eq!(battle.events.len(), DAMAGE_LOG_SIZE);
+        assert_eq!(battle.robots[1].health, 100000 - hits as i32);
+        // The oldest hits were dropped
+        assert!(battle.events[0].tick > 0);
+    }
+
+    #[test]
+    fn cores_see_the_battle_from_before_the_tick() {
+        // The runner leaves the line of fire on the same tick the gunner shoots, but the
+        // gunner aims at where the runner was at the beginning of the tick
+        for swap in [false, true] {
+            let mut robots = vec![
+                robot("gunner", "sht\n", 0, 0, (1, 0), 1),
+                robot("runner", "fwd\n", 3, 0, (0, 1), 2),
+            ];
+            if swap {
+                robots.reverse();
+            }
+            let mut battle = Battle::new(robots, 0);
+            step_game(&mut battle, &FIELD);
+            assert_eq!(battle.events.len(), 1);
+            assert_eq!(battle.events[0].target, "runner");
+        }
+    }
+
+    #[test]
+    fn robots_collect_crates_by_moving_onto_them() {
+        let mut battle = Battle::new(vec![robot("r", "fwd\n", 4, 3, (0, 1), 1)], 0);
+        battle.pickups.push(Pickup {
+            x: 4,
+            y: 4,
+            kind: PickupKind::Ammo,
+        });
+        let bullets = battle.robots[0].bullets;
+        step_game(&mut battle, &FIELD);
+        assert_eq!((battle.robots[0].x, battle.robots[0].y), (4, 4));
+        assert_eq!(battle.robots[0].bullets, bullets + AMMO_PICKUP);
+        assert!(battle.pickups.is_empty());
+    }
+
+    #[test]
+    fn contended_crates_stay_on_the_field() {
+        // Both robots reach the crate on the same tick, so neither of them gets it
+        let mut battle = Battle::new(
+            vec![
+                robot("up", "fwd\n", 4, 3, (0, 1), 1),
+                robot("down", "fwd\n", 4, 5, (0, -1), 2),
+            ],
+            0,
+        );
+        battle.pickups.push(Pickup {
+            x: 4,
+            y: 4,
+            kind: PickupKind::Ammo,
+        });
+        let bullets: Vec<i32> = battle.robots.iter().map(|r| r.bullets).collect();
+        step_game(&mut battle, &FIELD);
+        assert!(battle.robots.iter().all(|r| (r.x, r.y) == (4, 4)));
+        assert_eq!(
+            battle
+                .robots
+                .iter()
+                .map(|r| r.bullets)
+                .collect::<Vec<i32>>(),
+            bullets
+        );
+        assert_eq!(battle.pickups.len(), 1);
+    }
+
+    #[test]
+    fn random_crates_depend_only_on_the_seed() {
+        let spawn = |seed: u64| {
+            let mut battle = Battle::new(vec![robot("r", "", 0, 0, (0, 1), 1)], seed);
+            battle.pickup_rate = 1;
+            for _ in 0..MAX_RANDOM_PICKUPS + 3 {
+                step_game(&mut battle, &FIELD);
+            }
+            battle
+                .pickups
+                .iter()
+                .map(|p| (p.x, p.y, p.kind.id()))
+                .collect::<Vec<(i32, i32, i16)>>()
+        };
+
+        let pickups = spawn(3);
+        assert_eq!(pickups.len(), MAX_RANDOM_PICKUPS);
+        assert_eq!(pickups, spawn(3));
+        assert_ne!(pickups, spawn(4));
+        // Crates are never dropped on a robot or on another crate
+        assert!(!pickups.iter().any(|p| (p.0, p.1) == (0, 0)));
+        for (i, p) in pickups.iter().enumerate() {
+            assert!(!pickups[..i].iter().any(|q| (q.0, q.1) == (p.0, p.1)));
+        }
+    }
+
+    #[test]
+    fn shuffled_spawns_depend_only_on_the_seed() {
+        let spawns = |seed: u64, reverse: bool| {
+            let mut robots = vec![
+                robot("a", "", 0, 0, (0, 1), 1),
+                robot("b", "", 9, 0, (-1, 0), 1),
+                robot("c", "", 0, 9, (1, 0), 2),
+                robot("d", "", 9, 9, (0, -1), 2),
+            ];
+            if reverse {
+                robots.reverse();
+            }
+            let mut battle = Battle::new(robots, seed);
+            battle.shuffle_spawns();
+            let mut spawns: Vec<String> = battle
+                .robots
+                .iter()
+                .map(|r| format!("{} {} {} {:?}", r.name, r.x, r.y, r.direction))
+                .collect();
+            spawns.sort();
+            spawns
+        };
+
+        let unshuffled = vec![
+            "a 0 0 (0, 1)",
+            "b 9 0 (-1, 0)",
+            "c 0 9 (1, 0)",
+            "d 9 9 (0, -1)",
+        ];
+        let mut moved = false;
+        for seed in 0..10 {
+            let shuffled = spawns(seed, false);
+            assert_eq!(shuffled, spawns(seed, true));
+            // Robots only swap their places
+            let mut places: Vec<&str> = shuffled.iter().map(|s| &s[2..]).collect();
+            places.sort();
+            let mut expected: Vec<&str> = unshuffled.iter().map(|s| &s[2..]).collect();
+            expected.sort();
+            assert_eq!(places, expected);
+            moved |= shuffled != unshuffled;
+        }
+        assert!(moved);
+    }
+
+    // Executes the next n instructions of a robot alone on the field
+    fn exec(robot: &mut Robot, n: usize) -> Action {
+        exec_among(robot, &[], &TeamRadio::new(), &vec![], n)
+    }
+
+    // Executes the next n instructions of a robot sharing the field with others
+    fn exec_among(
+        robot: &mut Robot,
+        others: &[Robot],
+        radio: &TeamRadio,
+        pickups: &Vec<Pickup>,
+        n: usize,
+    ) -> Action {
+        let mut action = Action::None;
+        for _ in 0..n {
+            let mut robots = vec![robot.view()];
+            robots.extend(others.iter().map(|r| r.view()));
+            let world = Snapshot {
+                robots,
+                radio,
+                pickups,
+                field: &FIELD,
+            };
+            action = execute_instruction(robot, &world);
+        }
+        action
+    }
+
+    fn flags(r: &Robot) -> (bool, bool, bool) {
+        (r.core.e, r.core.g, r.core.l)
+    }
+
+    const DIRECTIONS: [(i32, i32); 4] = [(0, 1), (1, 0), (0, -1), (-1, 0)];
+
+    #[test]
+    fn mov_copies_values() {
+        let mut r = robot("r", "mov a, 5\nmov b, a\nmov c, b", 0, 0, (0, 1), 1);
+        exec(&mut r, 3);
+        assert_eq!((r.core.a, r.core.b, r.core.c), (5, 5, 5));
+        assert_eq!(r.core.ip, 3);
+    }
+
+    #[test]
+    fn constants_have_the_values_of_the_battle() {
+        let source = ".equ TEN 10\nmov a, WIDTH\nmov b, HEIGHT\nmov c, ID\nadd c, TEN";
+        let mut r = robot("r", source, 0, 0, (0, 1), 1);
+        r.id = 7;
+        exec(&mut r, 4);
+        assert_eq!((r.core.a, r.core.b, r.core.c), (10, 10, 17));
+    }
+
+    #[test]
+    fn arithmetic_updates_registers_and_flags() {
+        let mut r = robot(
+            "r",
+            "mov a, 5\nadd a, 3\nsub a, 10\nsub a, 0\nadd a, 2",
+            0,
+            0,
+            (0, 1),
+            1,
+        );
+        exec(&mut r, 2);
+        assert_eq!(r.core.a, 8);
+        assert_eq!(flags(&r), (false, true, false));
+        exec(&mut r, 1);
+        assert_eq!(r.core.a, -2);
+        assert_eq!(flags(&r), (false, false, true));
+        exec(&mut r, 1);
+        assert_eq!(r.core.a, -2);
+        exec(&mut r, 1);
+        assert_eq!(r.core.a, 0);
+        assert_eq!(flags(&r), (true, false, false));
+    }
+
+    #[test]
+    fn rnd_writes_non_negative_numbers() {
+        let mut r = robot("r", "rnd a\nrnd b\nrnd c", 0, 0, (0, 1), 1);
+        r.rng = Rng::new(3);
+        exec(&mut r, 3);
+        let mut rng = Rng::new(3);
+        let expected: Vec<i16> = (0..3).map(|_| (rng.next_u64() & 0x7fff) as i16).collect();
+        assert_eq!(vec![r.core.a, r.core.b, r.core.c], expected);
+    }
+
+    #[test]
+    fn rad_points_at_the_closest_enemy() {
+        let mut r = robot("r", "rad", 5, 5, (0, 1), 1);
+        let cases = [
+            ((5, 8), 0),  // Ahead
+            ((2, 6), 1),  // On the right, the robot faces +y
+            ((8, 5), -1), // On the left
+            ((5, 2), 1),  // Behind
+        ];
+        for ((x, y), dir) in cases {
+            let mut enemy = robot("enemy", "", x, y, (0, 1), 2);
+            enemy.id = 1;
+            let mut friend = robot("friend", "", 5, 6, (0, 1), 1);
+            friend.id = 2;
+            r.core.ip = 0;
+            exec_among(&mut r, &[enemy, friend], &TeamRadio::new(), &vec![], 1);
+            assert_eq!(r.core.dir, dir, "enemy at {:?}", (x, y));
         }
     }
 
-    robots.retain(|r| r.health > 0);
+    #[test]
+    fn chk_finds_enemies_in_the_area_of_interest() {
+        let mut r = robot("r", "chk\nchk", 5, 5, (0, 1), 1);
+        for ((x, y), found) in [((5, 7), (5, 7)), ((8, 5), (-1, -1))] {
+            let mut enemy = robot("enemy", "", x, y, (0, 1), 2);
+            enemy.id = 1;
+            let mut friend = robot("friend", "", 5, 6, (0, 1), 1);
+            friend.id = 2;
+            exec_among(&mut r, &[enemy, friend], &TeamRadio::new(), &vec![], 1);
+            assert_eq!((r.core.cx, r.core.cy), found, "enemy at {:?}", (x, y));
+        }
+    }
+
+    // Enemies of the robot at the given positions, on team 2
+    fn enemies(positions: &[(i32, i32)]) -> Vec<Robot> {
+        let mut out = vec![];
+        for (i, (x, y)) in positions.iter().enumerate() {
+            let mut enemy = robot(&format!("enemy{}", i), "", *x, *y, (0, 1), 2);
+            enemy.id = i + 1;
+            out.push(enemy);
+        }
+        out
+    }
+
+    #[test]
+    fn rad_is_relative_to_the_direction_of_the_robot() {
+        for d in DIRECTIONS {
+            let left = (d.1, -d.0);
+            let cases = [
+                ((d.0 * 3, d.1 * 3), 0),
+                ((left.0 * 3, left.1 * 3), -1),
+                ((-left.0 * 3, -left.1 * 3), 1),
+                ((-d.0 * 3, -d.1 * 3), 1),
+                // Ahead on the left
+                ((d.0 * 2 + left.0, d.1 * 2 + left.1), -1),
+            ];
+            for (offset, dir) in cases {
+                let mut r = robot("r", "rad", 5, 5, d, 1);
+                let others = enemies(&[(5 + offset.0, 5 + offset.1)]);
+                exec_among(&mut r, &others, &TeamRadio::new(), &vec![], 1);
+                assert_eq!(r.core.dir, dir, "facing {:?}, enemy at {:?}", d, offset);
+            }
+        }
+    }
+
+    #[test]
+    fn rad_picks_the_closest_enemy() {
+        // The enemy on the left is closer than the one ahead
+        let mut r = robot("r", "rad", 5, 5, (0, 1), 1);
+        exec_among(
+            &mut r,
+            &enemies(&[(5, 9), (8, 5)]),
+            &TeamRadio::new(),
+            &vec![],
+            1,
+        );
+        assert_eq!(r.core.dir, -1);
+
+        // Ties are broken by position, whatever the order of the robots
+        for positions in [[(2, 5), (8, 5)], [(8, 5), (2, 5)]] {
+            let mut r = robot("r", "rad", 5, 5, (0, 1), 1);
+            exec_among(&mut r, &enemies(&positions), &TeamRadio::new(), &vec![], 1);
+            assert_eq!(r.core.dir, 1);
+        }
+    }
+
+    #[test]
+    fn rad_keeps_the_direction_without_enemies() {
+        let mut r = robot("r", "rad", 5, 5, (0, 1), 1);
+        r.core.dir = 7;
+        let mut friend = robot("friend", "", 5, 8, (0, 1), 1);
+        friend.id = 1;
+        exec_among(&mut r, &[friend], &TeamRadio::new(), &vec![], 1);
+        assert_eq!(r.core.dir, 7);
+    }
+
+    #[test]
+    fn chk_picks_the_closest_enemy_in_the_area_of_interest() {
+        // The area of interest is (0, 1), (0, 2), (1, 0) and (-1, 0)
+        let cases = [
+            (vec![(5, 7), (6, 5)], (6, 5)),
+            (vec![(5, 6), (4, 5)], (4, 5)),
+            (vec![(4, 5), (5, 6)], (4, 5)),
+            // Close, but outside of the area
+            (vec![(5, 4), (6, 6), (5, 7)], (5, 7)),
+            (vec![(5, 4), (6, 6)], (-1, -1)),
+        ];
+        for (positions, found) in cases {
+            let mut r = robot("r", "chk", 5, 5, (0, 1), 1);
+            exec_among(&mut r, &enemies(&positions), &TeamRadio::new(), &vec![], 1);
+            assert_eq!((r.core.cx, r.core.cy), found, "enemies at {:?}", positions);
+        }
+    }
+
+    #[test]
+    fn fnd_finds_the_closest_pickup_of_a_kind() {
+        let mut r = robot("r", "fnd 0\nfnd 1\nfnd 3", 0, 0, (0, 1), 1);
+        let pickups = vec![
+            Pickup {
+                x: 6,
+                y: 6,
+                kind: PickupKind::Ammo,
+            },
+            Pickup {
+                x: 1,
+                y: 2,
+                kind: PickupKind::Health,
+            },
+        ];
+        let radio = TeamRadio::new();
+        exec_among(&mut r, &[], &radio, &pickups, 1);
+        assert_eq!((r.core.cx, r.core.cy), (1, 2));
+        exec_among(&mut r, &[], &radio, &pickups, 1);
+        assert_eq!((r.core.cx, r.core.cy), (6, 6));
+        exec_among(&mut r, &[], &radio, &pickups, 1);
+        assert_eq!((r.core.cx, r.core.cy), (-1, -1));
+    }
+
+    #[test]
+    fn snd_and_rcv_go_through_the_radio_of_the_team() {
+        let mut sender = robot("sender", "mov a, 7\nsnd a\nsnd 9", 0, 0, (0, 1), 1);
+        exec(&mut sender, 3);
+        assert_eq!(sender.outbox, vec![7, 9]);
+
+        let mut radio = TeamRadio::new();
+        radio.send(1, sender.id, &sender.name, 7, 0);
+        radio.send(2, 5, "enemy", 8, 0);
+        radio.send(1, sender.id, &sender.name, 9, 0);
+        let mut receiver = robot("receiver", "rcv a\nrcv b\nrcv c", 1, 0, (0, 1), 1);
+        receiver.id = 1;
+        receiver.core.msg = 2;
+        exec_among(&mut receiver, &[], &radio, &vec![], 3);
+        assert_eq!(
+            (receiver.core.a, receiver.core.b, receiver.core.c),
+            (7, 9, 0)
+        );
+        assert_eq!(receiver.core.msg, 0);
+    }
+
+    #[test]
+    fn messages_are_delivered_on_the_next_tick() {
+        let sender = robot("sender", "snd 5", 0, 0, (0, 1), 1);
+        let receiver = robot("receiver", "rcv a", 9, 9, (0, 1), 1);
+        let enemy = robot("enemy", "rcv a", 5, 5, (0, 1), 2);
+        let mut battle = Battle::new(vec![sender, receiver, enemy], 0);
+
+        step_game(&mut battle, &FIELD);
+        assert_eq!(battle.robots[1].core.a, 0);
+        assert!(!battle.radio.pending(1, battle.robots[1].id, 0).is_empty());
+
+        step_game(&mut battle, &FIELD);
+        assert_eq!(battle.robots[1].core.a, 5);
+        // Other teams don't hear it
+        assert_eq!(battle.robots[2].core.a, 0);
+    }
+
+    #[test]
+    fn shd_raises_the_shield() {
+        let mut r = robot("r", "shd", 0, 0, (0, 1), 1);
+        exec(&mut r, 1);
+        assert_eq!(r.shield, SHIELD_TICKS);
+    }
+
+    #[test]
+    fn logic_updates_registers_and_flags() {
+        let mut r = robot(
+            "r",
+            "mov a, 12\nand a, 10\nor a, 1\nxor a, 9\nnot b",
+            0,
+            0,
+            (0, 1),
+            1,
+        );
+        exec(&mut r, 2);
+        assert_eq!(r.core.a, 8);
+        exec(&mut r, 1);
+        assert_eq!(r.core.a, 9);
+        exec(&mut r, 1);
+        assert_eq!(r.core.a, 0);
+        assert_eq!(flags(&r), (true, false, false));
+        exec(&mut r, 1);
+        assert_eq!(r.core.b, -1);
+        assert_eq!(flags(&r), (false, false, true));
+    }
+
+    #[test]
+    fn cmp_only_updates_flags() {
+        let mut r = robot(
+            "r",
+            "mov a, 3\ncmp a, 3\ncmp a, 1\ncmp a, 7",
+            0,
+            0,
+            (0, 1),
+            1,
+        );
+        exec(&mut r, 2);
+        assert_eq!(flags(&r), (true, false, false));
+        exec(&mut r, 1);
+        assert_eq!(flags(&r), (false, true, false));
+        exec(&mut r, 1);
+        assert_eq!(flags(&r), (false, false, true));
+        assert_eq!(r.core.a, 3);
+    }
+
+    #[test]
+    fn jumps_follow_the_flags() {
+        // Taken jumps land on the target, the others fall through
+        let cases = [
+            ("mov a, 1\ncmp a, 1\nje target", 4),
+            ("mov a, 1\ncmp a, 2\nje target", 3),
+            ("mov a, 1\ncmp a, 0\njg target", 4),
+            ("mov a, 1\ncmp a, 1\njg target", 3),
+            ("mov a, 1\ncmp a, 2\njl target", 4),
+            ("mov a, 1\ncmp a, 1\njl target", 3),
+            ("nop\nnop\njmp target", 4),
+        ];
+        for (source, ip) in cases {
+            let source = format!("{}\nnop\ntarget:\nnop", source);
+            let mut r = robot("r", &source, 0, 0, (0, 1), 1);
+            exec(&mut r, 3);
+            assert_eq!(r.core.ip, ip, "{}", source);
+        }
+    }
+
+    #[test]
+    fn ip_wraps_around_the_program() {
+        let mut r = robot("r", "add a, 1", 0, 0, (0, 1), 1);
+        let radio = TeamRadio::new();
+        let pickups = vec![];
+        let world = Snapshot {
+            robots: vec![r.view()],
+            radio: &radio,
+            pickups: &pickups,
+            field: &FIELD,
+        };
+        step_robot(&mut r, &world);
+        assert_eq!(r.core.a, CYCLES_PER_TICK as i16);
+    }
+
+    #[test]
+    fn shots_only_use_energy_when_fired_at_a_target() {
+        let radio = TeamRadio::new();
+        let pickups = vec![];
+        let mut shooter = robot("shooter", "sht", 0, 0, (0, 1), 1);
+        let mut target = robot("target", "", 0, 3, (0, 1), 2);
+        target.id = 1;
+        shooter.energy = 50;
+        let recharge = shooter.energy_recharge;
+
+        // Nothing in the line of fire
+        let world = Snapshot {
+            robots: vec![shooter.view()],
+            radio: &radio,
+            pickups: &pickups,
+            field: &FIELD,
+        };
+        step_robot(&mut shooter, &world);
+        assert_eq!(shooter.energy, 50 + recharge);
+
+        // A target with the magazine loaded, then empty
+        let world = Snapshot {
+            robots: vec![shooter.view(), target.view()],
+            radio: &radio,
+            pickups: &pickups,
+            field: &FIELD,
+        };
+        shooter.magazine = 1;
+        assert!(matches!(step_robot(&mut shooter, &world), Action::Shoot(_)));
+        assert_eq!(shooter.energy, 50 + 2 * recharge - Opcode::Sht.energy());
+        assert_eq!(step_robot(&mut shooter, &world), Action::None);
+        assert_eq!(shooter.energy, 50 + 3 * recharge - Opcode::Sht.energy());
+    }
+
+    #[test]
+    fn robots_without_a_program_recharge() {
+        let mut r = robot("r", "", 0, 0, (0, 1), 1);
+        r.energy = 50;
+        let radio = TeamRadio::new();
+        let pickups = vec![];
+        let world = Snapshot {
+            robots: vec![r.view()],
+            radio: &radio,
+            pickups: &pickups,
+            field: &FIELD,
+        };
+        step_robot(&mut r, &world);
+        assert_eq!(r.energy, 50 + r.energy_recharge);
+    }
+
+    #[test]
+    fn read_only_registers_are_not_written() {
+        let mut r = robot(
+            "r",
+            "mov energy, 1\nmov loaded, 5\nmov shield, 9",
+            0,
+            0,
+            (0, 1),
+            1,
+        );
+        exec(&mut r, 3);
+        assert_eq!(r.energy, 100);
+        assert_eq!(r.magazine, 1);
+        assert_eq!(r.shield, 0);
+    }
+
+    #[test]
+    fn fwd_moves_in_every_direction() {
+        for d in DIRECTIONS {
+            let mut r = robot("r", "fwd", 5, 5, d, 1);
+            assert_eq!(exec(&mut r, 1), Action::Move(5 + d.0, 5 + d.1));
+        }
+    }
+
+    #[test]
+    fn fwd_stops_at_the_edge_of_the_field() {
+        let corners = [
+            ((0, 9), (0, 1)),
+            ((9, 0), (1, 0)),
+            ((0, 0), (0, -1)),
+            ((0, 0), (-1, 0)),
+        ];
+        for ((x, y), d) in corners {
+            let mut r = robot("r", "fwd", x, y, d, 1);
+            assert_eq!(exec(&mut r, 1), Action::Move(x, y));
+        }
+    }
+
+    #[test]
+    fn rotations_go_through_every_direction() {
+        // rol goes clockwise on the field, ror counterclockwise
+        for (i, d) in DIRECTIONS.iter().enumerate() {
+            let mut r = robot("r", "rol", 5, 5, *d, 1);
+            exec(&mut r, 1);
+            assert_eq!(r.direction, DIRECTIONS[(i + 1) % 4]);
+
+            let mut r = robot("r", "ror", 5, 5, *d, 1);
+            exec(&mut r, 1);
+            assert_eq!(r.direction, DIRECTIONS[(i + 3) % 4]);
+        }
+    }
+
+    #[test]
+    fn sht_hits_in_every_direction() {
+        for d in DIRECTIONS {
+            let mut battle = Battle::new(
+                vec![
+                    robot("gunner", "sht", 5, 5, d, 1),
+                    robot("target", "nop", 5 + d.0 * 3, 5 + d.1 * 3, d, 2),
+                ],
+                0,
+            );
+            step_game(&mut battle, &FIELD);
+            assert_eq!(battle.events.len(), 1, "{:?}", d);
+            assert_eq!(battle.events[0].target, "target");
+            // Shot in the back
+            assert_eq!(battle.events[0].side, HitSide::Rear);
+            assert_eq!(battle.robots[1].health, 90);
+        }
+    }
+
+    #[test]
+    fn sht_misses_robots_out_of_line_or_range() {
+        let mut gunner = robot("gunner", "sht", 0, 0, (1, 0), 1);
+        gunner.weapon.range = 4;
+        let mut battle = Battle::new(
+            vec![
+                gunner,
+                robot("beside", "nop", 2, 1, (0, 1), 2),
+                robot("behind", "nop", 0, 3, (0, 1), 2),
+                robot("far", "nop", 6, 0, (0, 1), 2),
+            ],
+            0,
+        );
+        step_game(&mut battle, &FIELD);
+        assert!(battle.events.is_empty());
+        assert_eq!(battle.robots[0].magazine, 0);
+    }
+
+    #[test]
+    fn sht_hits_the_closest_robot() {
+        let mut battle = Battle::new(
+            vec![
+                robot("gunner", "sht", 0, 0, (1, 0), 1),
+                robot("far", "nop", 5, 0, (0, 1), 2),
+                robot("near", "nop", 2, 0, (0, 1), 2),
+            ],
+            0,
+        );
+        step_game(&mut battle, &FIELD);
+        assert_eq!(battle.events.len(), 1);
+        assert_eq!(battle.events[0].target, "near");
+        assert_eq!(battle.events[0].side, HitSide::Side);
+    }
+
+    #[test]
+    fn rld_refills_the_magazine_after_reloading() {
+        let mut r = robot(
+            "r",
+            "sht\nrld\nwait:\nmov a, loaded\njmp wait",
+            5,
+            5,
+            (0, 1),
+            1,
+        );
+        exec(&mut r, 1);
+        assert_eq!((r.magazine, r.loaded()), (0, 0));
+
+        exec(&mut r, 1);
+        assert_eq!(r.reload_timer, r.weapon.reload_ticks);
+        assert_eq!(r.bullets, 100);
+
+        // The reload goes on while the core runs
+        let radio = TeamRadio::new();
+        let pickups = vec![];
+        for _ in 0..r.weapon.reload_ticks {
+            let world = Snapshot {
+                robots: vec![r.view()],
+                radio: &radio,
+                pickups: &pickups,
+                field: &FIELD,
+            };
+            step_robot(&mut r, &world);
+        }
+        assert_eq!((r.magazine, r.loaded(), r.bullets), (1, 1, 99));
+        assert_eq!(r.core.a, 1);
+    }
+
+    #[test]
+    fn bytecode_programs_are_disassembled_once_loaded() {
+        let compiled = robot("r", "loop: rad\nmov a, 65535\njmp loop", 0, 0, (0, 1), 1);
+        let bytes = encode(&Bytecode {
+            code: compiled.core.code.clone(),
+            lines: compiled.core.lines.clone(),
+        });
+        let mut core = Core::default();
+        core.load_bytecode(&bytes).unwrap();
+        assert_eq!(core.disassembly, disassemble(&compiled.core.code));
+
+        core.source = String::from("fwd");
+        core.compile().unwrap();
+        assert!(core.disassembly.0.is_empty());
+    }
+
+    #[test]
+    fn rejects_programs_using_missing_components() {
+        let mut r = Robot {
+            name: String::from("r"),
+            components: vec![Box::new(Motor), Box::new(Turret)],
+            ..Default::default()
+        };
+        r.core.source = String::from("fwd\nsht\nrad");
+        assert_eq!(
+            r.compile().err(),
+            Some(String::from(
+                "Line 3: r has no component providing instruction rad"
+            ))
+        );
+        assert!(r.core.code.is_empty());
+
+        r.core.source = String::from("fwd\nmov a, cx");
+        assert_eq!(
+            r.compile().err(),
+            Some(String::from(
+                "Line 2: r has no component providing register cx"
+            ))
+        );
+
+        r.core.source = String::from("fwd\nsht\nmov a, loaded\nadd a, energy");
+        assert!(r.compile().is_ok());
+    }
+
+    #[test]
+    fn rejects_programs_over_the_limits() {
+        let compile = |source: String| {
+            let mut core = Core {
+                source,
+                ..Default::default()
+            };
+            core.compile()
+        };
+
+        assert!(compile("fwd\n".repeat(MAX_INSTRUCTIONS)).is_ok());
+        assert!(compile("fwd\n".repeat(MAX_INSTRUCTIONS + 1)).is_err());
+        assert!(compile(" ".repeat(MAX_SOURCE_SIZE + 1)).is_err());
+
+        let labels: String = (0..=MAX_LABELS).map(|i| format!("l{}:\n", i)).collect();
+        assert!(compile(labels).is_err());
+    }
 }
@@ -0,0 +1,420 @@
+use super::*;
+
+use crate::bytecode::Register;
+
+// Comparison of an expectation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    pub fn from_name(name: &str) -> Option<Comparison> {
+        match name {
+            "==" => Some(Comparison::Eq),
+            "!=" => Some(Comparison::Ne),
+            "<" => Some(Comparison::Lt),
+            "<=" => Some(Comparison::Le),
+            ">" => Some(Comparison::Gt),
+            ">=" => Some(Comparison::Ge),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Comparison::Eq => "==",
+            Comparison::Ne => "!=",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+        }
+    }
+
+    pub fn holds(&self, left: i32, right: i32) -> bool {
+        match self {
+            Comparison::Eq => left == right,
+            Comparison::Ne => left != right,
+            Comparison::Lt => left < right,
+            Comparison::Le => left <= right,
+            Comparison::Gt => left > right,
+            Comparison::Ge => left >= right,
+        }
+    }
+}
+
+pub enum Check {
+    Position(i32, i32),
+    Direction(i32, i32),
+    Alive,
+    Dead,
+    DeadBy(u32), // Destroyed at the latest on the given tick
+    Property(String, Comparison, i32),
+}
+
+pub struct Expectation {
+    pub line: usize,
+    pub robot: String,
+    pub check: Check,
+}
+
+// Test of robot programs, written as a match configuration with a few more keys:
+//
+//   ticks 50
+//   expect Robot position 3 4
+//   expect Robot direction 0 -1
+//   expect Robot alive
+//   expect Robot 2 dead by 40
+//   expect Robot health >= 50
+//   expect Robot a == 5
+//
+// The match is run for the given number of ticks, then all expectations are checked.
+// Properties can be any register the robot can read, x, y, health, bullets, magazine,
+// shots_fired, shots_hit, distance or instructions.
+pub struct Scenario {
+    pub config: MatchConfig,
+    pub ticks: u32,
+    pub expectations: Vec<Expectation>,
+}
+
+fn parse_number<T: std::str::FromStr>(word: &str, line: usize) -> Result<T, String> {
+    match word.parse::<T>() {
+        Ok(n) => Ok(n),
+        Err(_) => Err(format!("Line {}: {} is not a number", line, word)),
+    }
+}
+
+impl Scenario {
+    pub fn load(path: &str) -> Result<Scenario, String> {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) => return Err(format!("Couldn't read {}: {}", path, e)),
+        };
+        let mut scenario = Scenario::parse(&source)?;
+        scenario.config.resolve_programs(path);
+        Ok(scenario)
+    }
+
+    pub fn parse(source: &str) -> Result<Scenario, String> {
+        // The lines of the scenario are blanked out, so the rest of the file can be
+        // parsed as a match configuration with the same line numbers
+        let mut config_source = String::new();
+        let mut scenario_lines = vec![];
+        for (i, text) in source.lines().enumerate() {
+            let code = text.split('#').next().unwrap_or("");
+            match code.split_whitespace().next() {
+                Some("ticks") | Some("expect") => {
+                    scenario_lines.push((i + 1, code));
+                    config_source.push('\n');
+                }
+                _ => {
+                    config_source.push_str(text);
+                    config_source.push('\n');
+                }
+            }
+        }
+
+        let mut scenario = Scenario {
+            config: MatchConfig::parse(&config_source)?,
+            ticks: 0,
+            expectations: vec![],
+        };
+        let mut has_ticks = false;
+        for (line, code) in scenario_lines {
+            let words: Vec<&str> = code.split_whitespace().collect();
+            if words[0] == "ticks" {
+                if words.len() != 2 {
+                    return Err(format!("Line {}: expected 1 argument", line));
+                }
+                scenario.ticks = parse_number(words[1], line)?;
+                has_ticks = true;
+            } else {
+                let expectation = scenario.parse_expectation(&words[1..], line)?;
+                scenario.expectations.push(expectation);
+            }
+        }
+
+        if !has_ticks {
+            return Err(String::from("The scenario has no ticks"));
+        }
+        Ok(scenario)
+    }
+
+    fn parse_expectation(&self, words: &[&str], line: usize) -> Result<Expectation, String> {
+        // Robot names can contain spaces, so look for the longest name that matches
+        let mut robot = None;
+        for n in (1..=words.len()).rev() {
+            let name = words[..n].join(" ");
+            if self.config.robots.iter().any(|r| r.name == name) {
+                robot = Some((name, n));
+                break;
+            }
+        }
+        let (robot, n) = match robot {
+            Some(robot) => robot,
+            None => return Err(format!("Line {}: expectation about an unknown robot", line)),
+        };
+
+        let check = match &words[n..] {
+            ["position", x, y] => Check::Position(parse_number(x, line)?, parse_number(y, line)?),
+            ["direction", x, y] => Check::Direction(parse_number(x, line)?, parse_number(y, line)?),
+            ["alive"] => Check::Alive,
+            ["dead"] => Check::Dead,
+            ["dead", "by", tick] => Check::DeadBy(parse_number(tick, line)?),
+            [property, op, value] => {
+                let op = match Comparison::from_name(op) {
+                    Some(op) => op,
+                    None => return Err(format!("Line {}: unknown comparison {}", line, op)),
+                };
+                Check::Property(property.to_string(), op, parse_number(value, line)?)
+            }
+            _ => return Err(format!("Line {}: invalid expectation", line)),
+        };
+        Ok(Expectation { line, robot, check })
+    }
+
+    // Runs the scenario and returns the expectations that weren't met
+    pub fn run(&self) -> Result<Vec<String>, String> {
+        let mut battle = self.config.start()?;
+        let field = Field {
+            x: 0,
+            y: 0,
+            width: self.config.width,
+            height: self.config.height,
+        };
+
+        let mut deaths: Vec<(String, u32)> = vec![];
+        for _ in 0..self.ticks {
+            step_game(&mut battle, &field);
+            for r in battle.destroyed.iter() {
+                if !deaths.iter().any(|(name, _)| *name == r.name) {
+                    deaths.push((r.name.clone(), battle.tick));
+                }
+            }
+        }
+
+        let mut failures = vec![];
+        for e in self.expectations.iter() {
+            // Registers are only known while the robot is alive
+            let alive = battle.robots.iter().find(|r| r.name == e.robot);
+            let robot = match alive {
+                Some(robot) => robot.summary(),
+                None => match battle.destroyed.iter().find(|r| r.name == e.robot) {
+                    Some(robot) => robot.clone(),
+                    None => return Err(format!("Robot {} isn't in the battle", e.robot)),
+                },
+            };
+            let death = deaths.iter().find(|(name, _)| *name == e.robot);
+
+            let failure = match &e.check {
+                Check::Position(x, y) if (robot.x, robot.y) != (*x, *y) => Some(format!(
+                    "expected position {} {}, found {} {}",
+                    x, y, robot.x, robot.y
+                )),
+                Check::Direction(x, y) if robot.direction != (*x, *y) => Some(format!(
+                    "expected direction {} {}, found {} {}",
+                    x, y, robot.direction.0, robot.direction.1
+                )),
+                Check::Alive if death.is_some() => Some(format!(
+                    "expected to be alive, destroyed on tick {}",
+                    death.unwrap().1
+                )),
+                Check::Dead if death.is_none() => Some(String::from("expected to be dead")),
+                Check::DeadBy(tick) => match death {
+                    Some((_, t)) if t <= tick => None,
+                    Some((_, t)) => Some(format!(
+                        "expected to be dead by tick {}, destroyed on tick {}",
+                        tick, t
+                    )),
+                    None => Some(format!("expected to be dead by tick {}", tick)),
+                },
+                Check::Property(name, op, value) => match property(&robot, alive, name) {
+                    Some(found) if op.holds(found, *value) => None,
+                    Some(found) => Some(format!(
+                        "expected {} {} {}, found {}",
+                        name,
+                        op.name(),
+                        value,
+                        found
+                    )),
+                    None => Some(format!("has no property {}", name)),
+                },
+                _ => None,
+            };
+            if let Some(failure) = failure {
+                failures.push(format!("Line {}: {} {}", e.line, e.robot, failure));
+            }
+        }
+        Ok(failures)
+    }
+}
+
+fn property(robot: &RobotSummary, alive: Option<&Robot>, name: &str) -> Option<i32> {
+    match name {
+        "x" => Some(robot.x),
+        "y" => Some(robot.y),
+        "health" => Some(robot.health),
+        "bullets" => Some(robot.bullets),
+        "magazine" => Some(robot.magazine),
+        "shots_fired" => Some(robot.stats.shots_fired as i32),
+        "shots_hit" => Some(robot.stats.shots_hit as i32),
+        "distance" => Some(robot.stats.distance as i32),
+        "instructions" => Some(robot.stats.instructions as i32),
+        _ => match alive {
+            Some(alive) if alive.has_register(name) => {
+                Register::from_name(name).map(|reg| alive.register(reg) as i32)
+            }
+            _ => None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes a scenario and its programs to a directory of their own and loads it
+    fn load(test: &str, source: &str, programs: &[(&str, &str)]) -> Scenario {
+        let dir =
+            std::env::temp_dir().join(format!("hbot-scenario-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, program) in programs {
+            fs::write(dir.join(name), program).unwrap();
+        }
+        let path = dir.join("test.scn");
+        fs::write(&path, source).unwrap();
+        Scenario::load(&path.to_string_lossy()).unwrap()
+    }
+
+    #[test]
+    fn parses_scenarios() {
+        let scenario = Scenario::parse(
+            "robot Space invader
+  team 1
+robot Target
+
+ticks 40 # Comments are allowed
+expect Space invader position 3 4
+expect Target direction 0 -1
+expect Target dead by 30
+expect Space invader health >= 50
+",
+        )
+        .unwrap();
+        assert_eq!(scenario.ticks, 40);
+        assert_eq!(scenario.config.robots.len(), 2);
+
+        let e = &scenario.expectations;
+        assert_eq!(e.len(), 4);
+        assert_eq!((e[0].line, e[0].robot.as_str()), (6, "Space invader"));
+        assert!(matches!(e[0].check, Check::Position(3, 4)));
+        assert!(matches!(e[1].check, Check::Direction(0, -1)));
+        assert!(matches!(e[2].check, Check::DeadBy(30)));
+        match &e[3].check {
+            Check::Property(name, op, value) => {
+                assert_eq!((name.as_str(), *op, *value), ("health", Comparison::Ge, 50))
+            }
+            _ => panic!("expected a property"),
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_scenarios() {
+        let errors = [
+            ("robot R\nexpect R alive", "The scenario has no ticks"),
+            ("ticks", "Line 1: expected 1 argument"),
+            ("ticks many", "Line 1: many is not a number"),
+            (
+                "ticks 5\nexpect Q alive",
+                "Line 2: expectation about an unknown robot",
+            ),
+            (
+                "robot R\nticks 5\nexpect R health ~ 5",
+                "Line 3: unknown comparison ~",
+            ),
+            (
+                "robot R\nticks 5\nexpect R sleeping",
+                "Line 3: invalid expectation",
+            ),
+            (
+                "robot R\nticks 5\nexpect R position 1 y",
+                "Line 3: y is not a number",
+            ),
+            // The rest of the file is a match configuration, with the same line numbers
+            (
+                "ticks 5\nexpect R alive\nrobot R\n  wings 2",
+                "Line 4: unknown key wings",
+            ),
+        ];
+        for (source, error) in errors {
+            assert_eq!(
+                Scenario::parse(source).err(),
+                Some(String::from(error)),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn reports_the_expectations_that_fail() {
+        let scenario = load(
+            "failures",
+            "robot Gunner
+  program gunner.hasm
+  position 5 0
+  team 1
+robot Target
+  program idle.hasm
+  position 5 5
+  team 2
+  health 30
+
+ticks 40
+expect Gunner alive
+expect Gunner shots_hit == 3
+expect Target dead by 30
+expect Gunner position 5 0
+expect Gunner dead
+expect Gunner shots_fired < 3
+expect Target dead by 5
+expect Target alive
+expect Target a == 0
+",
+            &[("gunner.hasm", "sht\nrld\n"), ("idle.hasm", "nop\n")],
+        );
+        let failures = scenario.run().unwrap();
+        assert_eq!(failures.len(), 5, "{:?}", failures);
+        assert_eq!(failures[0], "Line 16: Gunner expected to be dead");
+        assert!(failures[1].starts_with("Line 17: Gunner expected shots_fired < 3, found"));
+        assert!(failures[2].starts_with("Line 18: Target expected to be dead by tick 5, destroyed"));
+        assert!(failures[3].starts_with("Line 19: Target expected to be alive, destroyed"));
+        // Registers of destroyed robots aren't known
+        assert_eq!(failures[4], "Line 20: Target has no property a");
+    }
+
+    #[test]
+    fn health_and_energy_can_start_over_the_default_maximum() {
+        let scenario = load(
+            "maximum",
+            "robot Tank
+  program idle.hasm
+  health 200
+  energy 300
+pickup health 0 0
+
+ticks 5
+expect Tank health == 200
+expect Tank energy == 300
+",
+            &[("idle.hasm", "nop\n")],
+        );
+        assert_eq!(scenario.run().unwrap(), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,514 @@
+use super::*;
+
+use crate::json::*;
+use crate::ladder::*;
+use crate::report::*;
+use std::path::Path;
+
+// How contenders are paired up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TournamentMode {
+    RoundRobin, // Every contender plays every other contender
+    Bracket,    // Single elimination, the loser of every series is out
+}
+
+impl TournamentMode {
+    pub fn from_name(name: &str) -> Option<TournamentMode> {
+        match name {
+            "round-robin" => Some(TournamentMode::RoundRobin),
+            "bracket" => Some(TournamentMode::Bracket),
+            _ => None,
+        }
+    }
+}
+
+pub struct Contender {
+    pub name: String,
+    pub program: String, // Path of the program
+    pub hash: u64,       // Hash of the compiled program, for the ladder
+}
+
+// Results of a contender across the tournament
+#[derive(Clone)]
+pub struct Standing {
+    pub name: String,
+    pub wins: u32,
+    pub losses: u32,
+    pub draws: u32,
+    pub victory_ticks: u32, // Sum of the ticks it took to win each match
+    pub round: u32,         // Last round reached in a bracket
+}
+
+impl Standing {
+    pub fn average_ticks(&self) -> Option<f32> {
+        if self.wins == 0 {
+            return None;
+        }
+        Some(self.victory_ticks as f32 / self.wins as f32)
+    }
+}
+
+// Outcome of a single headless match
+pub enum MatchResult {
+    Win(usize, u32), // Index of the winning contender and ticks it took to win
+    Draw,
+}
+
+// Tournament between the programs of a directory. The map is a match configuration
+// with exactly two robots of different teams, which describe the two spawn sides.
+// The programs of the map's robots are replaced by the ones of the contenders.
+pub struct Tournament {
+    pub map: MatchConfig,
+    pub contenders: Vec<Contender>,
+    pub mode: TournamentMode,
+    pub seeds: u32, // Number of matches played on each side of every pairing
+    pub rejected: Vec<String>, // Errors of the programs that were left out
+    pub quiet: bool, // Don't print the results of the rounds of a bracket
+}
+
+impl Tournament {
+    pub fn new(
+        dir: &str,
+        map: &str,
+        mode: TournamentMode,
+        seeds: u32,
+    ) -> Result<Tournament, String> {
+        let map = MatchConfig::load(map)?;
+        if map.robots.len() != 2 {
+            return Err(format!(
+                "the map must have exactly 2 robots, found {}",
+                map.robots.len()
+            ));
+        }
+        if map.robots[0].team == map.robots[1].team {
+            return Err(String::from(
+                "the robots of the map must be on different teams",
+            ));
+        }
+
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => return Err(format!("Couldn't read {}: {}", dir, e)),
+        };
+        let mut paths: Vec<_> = entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| {
+                p.extension()
+                    .is_some_and(|ext| ext == "hasm" || ext == "hbc")
+            })
+            .collect();
+        paths.sort();
+
+        let mut tournament = Tournament {
+            map,
+            contenders: vec![],
+            mode,
+            seeds,
+            rejected: vec![],
+            quiet: false,
+        };
+        for path in paths {
+            let program = path.to_string_lossy().into_owned();
+            let hash = match read_program(&program).and_then(|file| program_hash(&file)) {
+                Ok(hash) => hash,
+                Err(e) => {
+                    tournament.rejected.push(format!("{}: {}", program, e));
+                    continue;
+                }
+            };
+            let contender = Contender {
+                name: contender_name(&path),
+                program,
+                hash,
+            };
+
+            // Programs that don't compile on both sides of the map are left out of the
+            // tournament, the sides can have different loadouts
+            let mut error = None;
+            for side in tournament.map.robots.iter() {
+                let mut config = side.clone();
+                config.name = contender.name.clone();
+                config.program = contender.program.clone();
+                if let Err(e) = config.spawn() {
+                    error = Some(e);
+                    break;
+                }
+            }
+            match error {
+                Some(e) => tournament
+                    .rejected
+                    .push(format!("{}: {}", contender.program, e)),
+                None => tournament.contenders.push(contender),
+            }
+        }
+
+        if tournament.contenders.len() < 2 {
+            return Err(format!("{} has less than 2 valid programs", dir));
+        }
+        Ok(tournament)
+    }
+
+    // Plays a single match, with the first contender on the first side of the map.
+    // Returns the result and the report of the match.
+    pub fn play(
+        &self,
+        first: usize,
+        second: usize,
+        seed: u64,
+    ) -> Result<(MatchResult, Json), String> {
+        let mut config = self.map.clone();
+        config.seed = seed;
+        for (side, &c) in [first, second].iter().enumerate() {
+            config.robots[side].name = self.contenders[c].name.clone();
+            config.robots[side].program = self.contenders[c].program.clone();
+        }
+
+        let mut battle = config.start()?;
+        let field = Field {
+            x: 0,
+            y: 0,
+            width: config.width,
+            height: config.height,
+        };
+        let result = match run_battle(&mut battle, &field, config.max_ticks) {
+            Some(team) if team == config.robots[0].team => MatchResult::Win(first, battle.tick),
+            Some(_) => MatchResult::Win(second, battle.tick),
+            None => MatchResult::Draw,
+        };
+        Ok((result, match_report(&config, &battle)))
+    }
+
+    // Plays every seed on both sides of the map, recording every match on the ladder
+    // and in the reports. Returns the number of matches won by each contender.
+    pub fn play_series(
+        &self,
+        a: usize,
+        b: usize,
+        standings: &mut [Standing],
+        ladder: &mut Ladder,
+        reports: &mut Vec<Json>,
+    ) -> Result<(u32, u32), String> {
+        let mut wins = (0, 0);
+        for s in 0..self.seeds {
+            let seed = self.map.seed.wrapping_add(s as u64);
+            for (first, second) in [(a, b), (b, a)] {
+                let (result, report) = self.play(first, second, seed)?;
+                reports.push(report);
+                let outcome = match result {
+                    MatchResult::Win(winner, _) if winner == a => Outcome::Win,
+                    MatchResult::Win(_, _) => Outcome::Loss,
+                    MatchResult::Draw => Outcome::Draw,
+                };
+                ladder.record(
+                    (self.contenders[a].hash, &self.contenders[a].name),
+                    (self.contenders[b].hash, &self.contenders[b].name),
+                    outcome,
+                )?;
+
+                match result {
+                    MatchResult::Win(winner, ticks) => {
+                        let loser = if winner == a { b } else { a };
+                        standings[winner].wins += 1;
+                        standings[winner].victory_ticks += ticks;
+                        standings[loser].losses += 1;
+                        if winner == a {
+                            wins.0 += 1;
+                        } else {
+                            wins.1 += 1;
+                        }
+                    }
+                    MatchResult::Draw => {
+                        standings[a].draws += 1;
+                        standings[b].draws += 1;
+                    }
+                }
+            }
+        }
+        Ok(wins)
+    }
+
+    fn log(&self, text: String) {
+        if !self.quiet {
+            println!("{}", text);
+        }
+    }
+
+    // Runs the whole tournament and returns the standings, best contender first
+    pub fn run(
+        &self,
+        ladder: &mut Ladder,
+        reports: &mut Vec<Json>,
+    ) -> Result<Vec<Standing>, String> {
+        let mut standings: Vec<Standing> = self
+            .contenders
+            .iter()
+            .map(|c| Standing {
+                name: c.name.clone(),
+                wins: 0,
+                losses: 0,
+                draws: 0,
+                victory_ticks: 0,
+                round: 1,
+            })
+            .collect();
+
+        match self.mode {
+            TournamentMode::RoundRobin => {
+                for a in 0..self.contenders.len() {
+                    for b in a + 1..self.contenders.len() {
+                        self.play_series(a, b, &mut standings, ladder, reports)?;
+                    }
+                }
+            }
+
+            TournamentMode::Bracket => {
+                let mut alive: Vec<usize> = (0..self.contenders.len()).collect();
+                let mut round = 1;
+                while alive.len() > 1 {
+                    let mut next = vec![];
+                    for pair in alive.chunks(2) {
+                        if pair.len() == 1 {
+                            self.log(format!(
+                                "Round {}: {} advances with a bye",
+                                round, standings[pair[0]].name
+                            ));
+                            standings[pair[0]].round += 1;
+                            next.push(pair[0]);
+                            continue;
+                        }
+
+                        // Ties go to the contender that comes first in the bracket
+                        let (a, b) = (pair[0], pair[1]);
+                        let wins = self.play_series(a, b, &mut standings, ladder, reports)?;
+                        let (winner, loser) = if wins.1 > wins.0 { (b, a) } else { (a, b) };
+                        let verb = if wins.0 == wins.1 {
+                            "advances on a tie over"
+                        } else {
+                            "beats"
+                        };
+                        self.log(format!(
+                            "Round {}: {} {} {} {}-{}",
+                            round,
+                            standings[winner].name,
+                            verb,
+                            standings[loser].name,
+                            std::cmp::max(wins.0, wins.1),
+                            std::cmp::min(wins.0, wins.1)
+                        ));
+                        standings[winner].round += 1;
+                        next.push(winner);
+                    }
+                    alive = next;
+                    round += 1;
+                }
+            }
+        }
+
+        standings.sort_by(|a, b| {
+            let a_ticks = a.average_ticks().unwrap_or(f32::MAX);
+            let b_ticks = b.average_ticks().unwrap_or(f32::MAX);
+            b.round
+                .cmp(&a.round)
+                .then(b.wins.cmp(&a.wins))
+                .then(b.draws.cmp(&a.draws))
+                .then(a.losses.cmp(&b.losses))
+                .then(a_ticks.total_cmp(&b_ticks))
+                .then(a.name.cmp(&b.name))
+        });
+        Ok(standings)
+    }
+}
+
+// The extension is kept, so bot.hasm and bot.hbc don't get the same name
+fn contender_name(path: &Path) -> String {
+    match path.file_name() {
+        Some(stem) => stem.to_string_lossy().into_owned(),
+        None => path.to_string_lossy().into_owned(),
+    }
+}
+
+pub fn print_standings(standings: &[Standing]) {
+    println!(
+        "{:<6}{:<24}{:>6}{:>8}{:>7}{:>12}",
+        "Rank", "Program", "Wins", "Losses", "Draws", "Avg ticks"
+    );
+    for (i, s) in standings.iter().enumerate() {
+        let ticks = match s.average_ticks() {
+            Some(ticks) => format!("{:.1}", ticks),
+            None => String::from("-"),
+        };
+        println!(
+            "{:<6}{:<24}{:>6}{:>8}{:>7}{:>12}",
+            i + 1,
+            s.name,
+            s.wins,
+            s.losses,
+            s.draws,
+            ticks
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP: &str = "field 10 10
+max_ticks 200
+
+robot West
+  position 0 0
+  direction 1 0
+  team 1
+
+robot East
+  position 5 0
+  direction -1 0
+  team 2
+";
+
+    // Writes the map and the programs of a test to a directory of its own, returning
+    // the path of the directory
+    fn write_programs(test: &str, map: &str, programs: &[(&str, &str)]) -> String {
+        let dir =
+            std::env::temp_dir().join(format!("hbot-tournament-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("map.cfg"), map).unwrap();
+        for (name, source) in programs {
+            fs::write(dir.join(name), source).unwrap();
+        }
+        dir.to_string_lossy().into_owned()
+    }
+
+    // Bravo shoots whoever is in front of it, alpha and charlie do nothing
+    fn tournament(test: &str, mode: TournamentMode) -> Tournament {
+        let dir = write_programs(
+            test,
+            MAP,
+            &[
+                ("alpha.hasm", "nop\n"),
+                ("bravo.hasm", "sht\nrld\n"),
+                ("charlie.hasm", "nop\n"),
+            ],
+        );
+        let map = format!("{}/map.cfg", dir);
+        let mut tournament = Tournament::new(&dir, &map, mode, 1).unwrap();
+        tournament.quiet = true;
+        tournament
+    }
+
+    fn summary(standings: &[Standing]) -> Vec<(&str, u32, u32, u32, u32)> {
+        standings
+            .iter()
+            .map(|s| (s.name.as_str(), s.round, s.wins, s.losses, s.draws))
+            .collect()
+    }
+
+    #[test]
+    fn round_robin_pairs_every_contender() {
+        let tournament = tournament("round-robin", TournamentMode::RoundRobin);
+        let mut reports = vec![];
+        let standings = tournament.run(&mut Ladder::new(), &mut reports).unwrap();
+
+        // 3 pairings, played on both sides of the map
+        assert_eq!(reports.len(), 6);
+        // Ties are broken by name
+        assert_eq!(
+            summary(&standings),
+            vec![
+                ("bravo.hasm", 1, 4, 0, 0),
+                ("alpha.hasm", 1, 0, 2, 2),
+                ("charlie.hasm", 1, 0, 2, 2),
+            ]
+        );
+        assert!(standings[0].average_ticks().is_some());
+        assert_eq!(standings[1].average_ticks(), None);
+    }
+
+    #[test]
+    fn brackets_advance_the_winners() {
+        // Alpha and bravo play the first round while charlie gets a bye, then bravo
+        // beats charlie in the final
+        let tournament = tournament("bracket", TournamentMode::Bracket);
+        let mut reports = vec![];
+        let standings = tournament.run(&mut Ladder::new(), &mut reports).unwrap();
+
+        assert_eq!(reports.len(), 4);
+        assert_eq!(
+            summary(&standings),
+            vec![
+                ("bravo.hasm", 3, 4, 0, 0),
+                ("charlie.hasm", 2, 0, 2, 0),
+                ("alpha.hasm", 1, 0, 2, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_out_programs_that_dont_compile() {
+        let dir = write_programs(
+            "rejected",
+            MAP,
+            &[
+                ("alpha.hasm", "nop\n"),
+                ("broken.hasm", "fly\n"),
+                ("charlie.hasm", "nop\n"),
+                ("notes.txt", "not a program"),
+            ],
+        );
+        let map = format!("{}/map.cfg", dir);
+        let tournament = Tournament::new(&dir, &map, TournamentMode::RoundRobin, 1).unwrap();
+        let names: Vec<&str> = tournament
+            .contenders
+            .iter()
+            .map(|c| c.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["alpha.hasm", "charlie.hasm"]);
+        assert_eq!(tournament.rejected.len(), 1);
+        assert!(tournament.rejected[0].contains("broken.hasm"));
+    }
+
+    #[test]
+    fn programs_must_compile_on_every_side() {
+        // The east side has no turret, so the shooter can't play there
+        let map = MAP.replace("team 2", "team 2\n  components motor sensors");
+        let dir = write_programs(
+            "sides",
+            &map,
+            &[
+                ("alpha.hasm", "nop\n"),
+                ("bravo.hasm", "sht\nrld\n"),
+                ("charlie.hasm", "nop\n"),
+            ],
+        );
+        let map = format!("{}/map.cfg", dir);
+        let tournament = Tournament::new(&dir, &map, TournamentMode::RoundRobin, 1).unwrap();
+        assert_eq!(tournament.contenders.len(), 2);
+        assert_eq!(tournament.rejected.len(), 1);
+        assert!(tournament.rejected[0].contains("bravo.hasm"));
+    }
+
+    #[test]
+    fn needs_two_valid_programs() {
+        let dir = write_programs(
+            "lonely",
+            MAP,
+            &[("alpha.hasm", "nop\n"), ("broken.hasm", "fly\n")],
+        );
+        let map = format!("{}/map.cfg", dir);
+        assert_eq!(
+            Tournament::new(&dir, &map, TournamentMode::RoundRobin, 1).err(),
+            Some(format!("{} has less than 2 valid programs", dir))
+        );
+    }
+
+    #[test]
+    fn names_keep_the_extension() {
+        assert_ne!(
+            contender_name(Path::new("bots/bot.hasm")),
+            contender_name(Path::new("bots/bot.hbc"))
+        );
+    }
+}